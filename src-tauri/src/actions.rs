@@ -0,0 +1,142 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Something the user can trigger from the tray, a global shortcut or the
+/// command line. In settings, actions without an argument are plain strings,
+/// e.g. `"toggle_window"`, and the others are objects such as
+/// `{ "quick_command": "Lights off" }`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Do nothing.
+    None,
+    /// Show the window, or hide it if it is shown.
+    ToggleWindow,
+    /// Show the window and start listening for a voice command.
+    TriggerVoicePipeline,
+    /// Open the settings page.
+    OpenSettings,
+    /// Start over without the context of the current conversation.
+    NewConversation,
+    /// Send the text of the quick command with this name from settings.
+    QuickCommand(String),
+    /// Use the Assist pipeline with this id for the next requests.
+    SwitchPipeline(String),
+}
+
+// Argument separator in the string form, e.g. `quick_command:Lights off`.
+const SEPARATOR: char = ':';
+
+// The string form used for tray menu ids and `--action`.
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::None => write!(f, "none"),
+            Action::ToggleWindow => write!(f, "toggle_window"),
+            Action::TriggerVoicePipeline => write!(f, "trigger_voice_pipeline"),
+            Action::OpenSettings => write!(f, "open_settings"),
+            Action::NewConversation => write!(f, "new_conversation"),
+            Action::QuickCommand(name) => write!(f, "quick_command{}{}", SEPARATOR, name),
+            Action::SwitchPipeline(id) => write!(f, "switch_pipeline{}{}", SEPARATOR, id),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match s.split_once(SEPARATOR) {
+            Some((name, argument)) => (name, Some(argument.to_string())),
+            None => (s, None),
+        };
+
+        match (name, argument) {
+            ("none", None) => Ok(Action::None),
+            ("toggle_window", None) => Ok(Action::ToggleWindow),
+            ("trigger_voice_pipeline", None) => Ok(Action::TriggerVoicePipeline),
+            ("open_settings", None) => Ok(Action::OpenSettings),
+            ("new_conversation", None) => Ok(Action::NewConversation),
+            ("quick_command", Some(name)) if !name.is_empty() => Ok(Action::QuickCommand(name)),
+            ("switch_pipeline", Some(id)) if !id.is_empty() => Ok(Action::SwitchPipeline(id)),
+            ("quick_command", _) => Err("Expected quick_command:NAME".to_string()),
+            ("switch_pipeline", _) => Err("Expected switch_pipeline:PIPELINE_ID".to_string()),
+            _ => Err(format!(
+                "Unknown action \"{}\", expected one of: none, toggle_window, \
+                 trigger_voice_pipeline, open_settings, new_conversation, \
+                 quick_command:NAME, switch_pipeline:PIPELINE_ID",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!("none".parse(), Ok(Action::None));
+        assert_eq!("toggle_window".parse(), Ok(Action::ToggleWindow));
+        assert_eq!(
+            "trigger_voice_pipeline".parse(),
+            Ok(Action::TriggerVoicePipeline)
+        );
+        assert_eq!("open_settings".parse(), Ok(Action::OpenSettings));
+        assert_eq!("new_conversation".parse(), Ok(Action::NewConversation));
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(
+            "quick_command:Lights off".parse(),
+            Ok(Action::QuickCommand("Lights off".to_string()))
+        );
+        // Only the first separator splits, pipeline ids may contain more.
+        assert_eq!(
+            "switch_pipeline:a:b".parse(),
+            Ok(Action::SwitchPipeline("a:b".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_actions() {
+        assert!("".parse::<Action>().is_err());
+        assert!("Toggle_Window".parse::<Action>().is_err());
+        assert!("toggle_window:extra".parse::<Action>().is_err());
+        assert!("quick_command".parse::<Action>().is_err());
+        assert!("quick_command:".parse::<Action>().is_err());
+        assert!("switch_pipeline".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn round_trips_through_strings() {
+        for action in [
+            Action::None,
+            Action::ToggleWindow,
+            Action::TriggerVoicePipeline,
+            Action::OpenSettings,
+            Action::NewConversation,
+            Action::QuickCommand("Lights off".to_string()),
+            Action::SwitchPipeline("01hvq".to_string()),
+        ] {
+            assert_eq!(action.to_string().parse(), Ok(action));
+        }
+    }
+
+    #[test]
+    fn serializes_like_settings() {
+        assert_eq!(
+            serde_json::to_value(Action::ToggleWindow).unwrap(),
+            json!("toggle_window")
+        );
+        assert_eq!(
+            serde_json::to_value(Action::QuickCommand("Lights off".to_string())).unwrap(),
+            json!({ "quick_command": "Lights off" })
+        );
+    }
+}
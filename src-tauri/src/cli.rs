@@ -0,0 +1,30 @@
+// Minimal command line parsing. Arguments are few and also arrive through the
+// single instance callback, so they are matched by hand rather than with a
+// full parser.
+
+// Whether `flag` was passed on its own, e.g. `--trigger-voice`.
+pub fn has(args: &[String], flag: &str) -> bool {
+    args.iter().any(|arg| arg == flag)
+}
+
+// Value of `flag VALUE` or `flag=VALUE`, if given.
+pub fn value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().enumerate().find_map(|(index, arg)| {
+        if arg == flag {
+            args.get(index + 1).cloned()
+        } else {
+            arg.strip_prefix(flag)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(str::to_string)
+        }
+    })
+}
+
+// Arguments following subcommand `name`, e.g. `pipelines list`. Subcommands
+// have to come first.
+pub fn subcommand<'a>(args: &'a [String], name: &str) -> Option<&'a [String]> {
+    match args.get(1) {
+        Some(arg) if arg == name => Some(&args[2..]),
+        _ => None,
+    }
+}
@@ -0,0 +1,123 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::home_assistant::WebSocketError;
+use crate::http::HttpError;
+use crate::settings::FieldError;
+
+// Kinds of failure, sent to the webview as `code` so it can handle them
+// without matching on messages. The names are part of the interface with the
+// webview, don't rename them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // Reading or writing files, e.g. a read-only config directory.
+    Io,
+    // Settings that are invalid or refer to something that doesn't exist.
+    Config,
+    // Showing, hiding or navigating the window.
+    Window,
+    // A server that can't be reached or answers with an error.
+    Network,
+    // A server whose TLS certificate isn't trusted, e.g. because it was issued
+    // by a private CA missing from the network settings.
+    Tls,
+    // A server that rejected the access token or API key.
+    Auth,
+}
+
+// Returned by every command, so failures end up in the UI rather than
+// taking down the app.
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    pub code: ErrorKind,
+    pub message: String,
+    // Per-field problems, set when settings fail validation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorKind, message: impl Into<String>) -> Self {
+        CommandError {
+            code,
+            message: message.into(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// Lets startup fail with the message, e.g. in the setup hook.
+impl std::error::Error for CommandError {}
+
+// Errors from the settings module are plain strings, and are about settings.
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::new(ErrorKind::Config, message)
+    }
+}
+
+impl From<std::io::Error> for CommandError {
+    fn from(error: std::io::Error) -> Self {
+        CommandError::new(ErrorKind::Io, error.to_string())
+    }
+}
+
+impl From<url::ParseError> for CommandError {
+    fn from(error: url::ParseError) -> Self {
+        CommandError::new(ErrorKind::Config, format!("Invalid URL: {}", error))
+    }
+}
+
+impl From<serde_json::Error> for CommandError {
+    fn from(error: serde_json::Error) -> Self {
+        CommandError::new(ErrorKind::Config, error.to_string())
+    }
+}
+
+impl From<Vec<FieldError>> for CommandError {
+    fn from(errors: Vec<FieldError>) -> Self {
+        CommandError {
+            code: ErrorKind::Config,
+            message: format!("Settings are invalid ({} errors)", errors.len()),
+            errors,
+        }
+    }
+}
+
+impl From<HttpError> for CommandError {
+    fn from(error: HttpError) -> Self {
+        let code = match error {
+            HttpError::Tls(_) => ErrorKind::Tls,
+            HttpError::Auth(_) => ErrorKind::Auth,
+            HttpError::Unreachable(_) | HttpError::Timeout(_) | HttpError::Other(_) => {
+                ErrorKind::Network
+            }
+        };
+        CommandError::new(code, error.to_string())
+    }
+}
+
+impl From<WebSocketError> for CommandError {
+    fn from(error: WebSocketError) -> Self {
+        let code = match error {
+            WebSocketError::Tls(_) => ErrorKind::Tls,
+            WebSocketError::Auth(_) => ErrorKind::Auth,
+            _ => ErrorKind::Network,
+        };
+        CommandError::new(code, error.to_string())
+    }
+}
+
+// Commands only call Tauri to manage the window.
+impl From<tauri::Error> for CommandError {
+    fn from(error: tauri::Error) -> Self {
+        CommandError::new(ErrorKind::Window, error.to_string())
+    }
+}
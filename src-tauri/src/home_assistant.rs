@@ -0,0 +1,138 @@
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tauri::async_runtime::JoinHandle;
+use tauri::Manager;
+
+use crate::settings::SettingsStore;
+
+mod assist;
+mod connection_test;
+mod conversation;
+pub mod pipelines;
+mod queue;
+mod supervisor;
+mod websocket;
+
+pub use assist::{run_text, run_text_rest, PipelineRun, PipelineStage};
+pub use connection_test::{test_connection, ConnectionTest};
+pub use queue::{CommandQueue, QueuedCommand};
+pub use supervisor::ConnectionStatus;
+pub use websocket::{Connection, Subscription, WebSocketError};
+
+// The app's own connection to Home Assistant, next to the webview's, so the
+// tray, global shortcuts and the command line can use Home Assistant without
+// the window.
+#[derive(Default)]
+pub struct HomeAssistantClient {
+    connection: Mutex<Option<Arc<Connection>>>,
+    // The server and network settings the connection was made with, to only
+    // reconnect when they change.
+    target: Mutex<Option<Value>>,
+    supervisor: Mutex<Option<JoinHandle<()>>>,
+    status: Mutex<ConnectionStatus>,
+    // Continued by each pipeline run until a new conversation is started.
+    conversation_id: Mutex<Option<String>>,
+}
+
+impl HomeAssistantClient {
+    // The open connection, if there is one.
+    pub fn connection(&self) -> Result<Arc<Connection>, WebSocketError> {
+        match &*self.connection.lock().unwrap() {
+            Some(connection) if !connection.is_closed() => Ok(connection.clone()),
+            _ => Err(WebSocketError::Closed(
+                "Not connected to Home Assistant".to_string(),
+            )),
+        }
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn conversation_id(&self) -> Option<String> {
+        self.conversation_id.lock().unwrap().clone()
+    }
+
+    fn set_conversation_id(&self, conversation_id: Option<String>) {
+        *self.conversation_id.lock().unwrap() = conversation_id;
+    }
+
+    pub fn new_conversation(&self) {
+        self.set_conversation_id(None);
+    }
+
+    fn set_status(&self, app_handle: &tauri::AppHandle, status: ConnectionStatus) {
+        *self.status.lock().unwrap() = status.clone();
+        crate::tray::set_connection_status(app_handle, &status);
+        if let Err(e) = app_handle.emit_all("connection-status", status) {
+            log::warn!("Could not emit connection-status event: {}", e);
+        }
+    }
+
+    // Record the status and connection of the supervisor connecting to
+    // `target`. Returns false if settings have changed since, in which case
+    // that supervisor should stop.
+    fn report(
+        &self,
+        app_handle: &tauri::AppHandle,
+        target: &Option<Value>,
+        status: ConnectionStatus,
+        connection: Option<Arc<Connection>>,
+    ) -> bool {
+        let current = self.target.lock().unwrap();
+        if *current != *target {
+            return false;
+        }
+        *self.connection.lock().unwrap() = connection;
+        self.set_status(app_handle, status);
+        true
+    }
+}
+
+// Connect to the server of the active profile in the background, replacing
+// any connection to a server that is no longer the one in settings. The
+// connection is kept up until settings change.
+pub fn connect(app_handle: &tauri::AppHandle) {
+    let settings = app_handle.state::<SettingsStore>().effective().settings;
+    let home_assistant = match settings.home_assistant() {
+        Ok(home_assistant) => home_assistant.clone(),
+        Err(e) => {
+            log::warn!("Not connecting to Home Assistant: {}", e);
+            return;
+        }
+    };
+
+    let client = app_handle.state::<HomeAssistantClient>();
+    let target = serde_json::to_value((&home_assistant, &settings.network)).ok();
+    let mut current = client.target.lock().unwrap();
+    if *current == target {
+        return;
+    }
+    *current = target.clone();
+
+    if let Some(supervisor) = client.supervisor.lock().unwrap().take() {
+        supervisor.abort();
+    }
+    *client.connection.lock().unwrap() = None;
+    // Conversations belong to the server they were started on.
+    client.new_conversation();
+
+    if home_assistant.access_token.is_empty() {
+        log::info!("No access token set, not connecting to Home Assistant");
+        client.set_status(app_handle, ConnectionStatus::NotConfigured);
+        return;
+    }
+    if home_assistant.rest_only {
+        log::info!("REST only, not opening a websocket to Home Assistant");
+        client.set_status(app_handle, ConnectionStatus::RestOnly);
+        return;
+    }
+
+    let supervisor = tauri::async_runtime::spawn(supervisor::supervise(
+        app_handle.clone(),
+        home_assistant,
+        settings.network,
+        target,
+    ));
+    *client.supervisor.lock().unwrap() = Some(supervisor);
+}
@@ -0,0 +1,721 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tauri::Manager;
+use tokio::time::Instant;
+
+use super::{conversation, HomeAssistantClient, WebSocketError};
+use crate::http::HttpError;
+use crate::settings::SettingsStore;
+
+// The agent Home Assistant uses when none is given.
+const DEFAULT_AGENT: &str = "conversation.home_assistant";
+
+// How long to wait for a run to start. After that the run may take as long
+// as Home Assistant gives it in `runner_data.timeout`, plus a margin for
+// Home Assistant to report its own timeout first.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+const TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+
+// Mirrors the types in src/types/homeAssistantAssist.ts, which follow the
+// Home Assistant frontend.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineStage {
+    WakeWord,
+    Stt,
+    Intent,
+    Tts,
+}
+
+// Where a run is at, `stage` in `PipelineRun`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStage {
+    Ready,
+    WakeWord,
+    Stt,
+    Intent,
+    Tts,
+    Done,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunnerData {
+    pub stt_binary_handler_id: Option<u8>,
+    // Seconds the run may take. Without it `START_TIMEOUT` applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunStart {
+    pub pipeline: String,
+    pub language: String,
+    pub runner_data: RunnerData,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipelineError {
+    pub code: String,
+    pub message: String,
+}
+
+// Only shown to the user, so missing fields are left empty.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeechMetadata {
+    pub language: String,
+    pub format: String,
+    pub codec: String,
+    pub bit_rate: u32,
+    pub sample_rate: u32,
+    pub channel: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AudioStart {
+    #[serde(default)]
+    pub engine: String,
+    #[serde(default)]
+    pub metadata: SpeechMetadata,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WakeWordOutput {
+    pub ww_id: String,
+    pub timestamp: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WakeWordEnd {
+    pub wake_word_output: Option<WakeWordOutput>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SttOutput {
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SttEnd {
+    pub stt_output: SttOutput,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IntentStart {
+    #[serde(default)]
+    pub engine: String,
+    #[serde(default)]
+    pub language: String,
+    pub intent_input: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Speech {
+    pub speech: String,
+    #[serde(default)]
+    pub extra_data: Value,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IntentSpeech {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plain: Option<Speech>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssml: Option<Speech>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IntentResponse {
+    // `action_done`, `query_answer` or `error`.
+    pub response_type: String,
+    #[serde(default)]
+    pub language: String,
+    pub speech: Option<IntentSpeech>,
+    // Targets, or the error code for `error` responses.
+    #[serde(default)]
+    pub data: Value,
+}
+
+// The reply of a conversation agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConversationResult {
+    pub conversation_id: Option<String>,
+    pub response: IntentResponse,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IntentEnd {
+    pub intent_output: ConversationResult,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TtsStart {
+    #[serde(default)]
+    pub engine: String,
+    #[serde(default)]
+    pub language: String,
+    pub voice: Option<String>,
+    pub tts_input: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResolvedMediaSource {
+    pub url: String,
+    pub mime_type: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TtsEnd {
+    pub tts_output: ResolvedMediaSource,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum KnownEvent {
+    #[serde(rename = "run-start")]
+    RunStart(RunStart),
+    #[serde(rename = "run-end")]
+    RunEnd(Value),
+    #[serde(rename = "error")]
+    Error(PipelineError),
+    #[serde(rename = "wake_word-start")]
+    WakeWordStart(AudioStart),
+    #[serde(rename = "wake_word-end")]
+    WakeWordEnd(WakeWordEnd),
+    #[serde(rename = "stt-start")]
+    SttStart(AudioStart),
+    #[serde(rename = "stt-end")]
+    SttEnd(SttEnd),
+    #[serde(rename = "intent-start")]
+    IntentStart(IntentStart),
+    #[serde(rename = "intent-end")]
+    IntentEnd(IntentEnd),
+    #[serde(rename = "tts-start")]
+    TtsStart(TtsStart),
+    #[serde(rename = "tts-end")]
+    TtsEnd(TtsEnd),
+}
+
+impl KnownEvent {
+    // The event of type `kind`, or `None` for types this app doesn't know.
+    fn parse(kind: &str, data: Value) -> Option<Result<Self, serde_json::Error>> {
+        use serde_json::from_value;
+
+        let event = match kind {
+            "run-start" => from_value(data).map(KnownEvent::RunStart),
+            "run-end" => Ok(KnownEvent::RunEnd(data)),
+            "error" => from_value(data).map(KnownEvent::Error),
+            "wake_word-start" => from_value(data).map(KnownEvent::WakeWordStart),
+            "wake_word-end" => from_value(data).map(KnownEvent::WakeWordEnd),
+            "stt-start" => from_value(data).map(KnownEvent::SttStart),
+            "stt-end" => from_value(data).map(KnownEvent::SttEnd),
+            "intent-start" => from_value(data).map(KnownEvent::IntentStart),
+            "intent-end" => from_value(data).map(KnownEvent::IntentEnd),
+            "tts-start" => from_value(data).map(KnownEvent::TtsStart),
+            "tts-end" => from_value(data).map(KnownEvent::TtsEnd),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+// Newer versions of Home Assistant send events this app doesn't know yet,
+// e.g. `stt-vad-start`. Those are passed on as they are, as are known events
+// that don't parse, after a warning.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum PipelineEventKind {
+    Known(KnownEvent),
+    Other {
+        #[serde(rename = "type")]
+        kind: String,
+        data: Value,
+    },
+}
+
+impl<'de> Deserialize<'de> for PipelineEventKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Event {
+            #[serde(rename = "type")]
+            kind: String,
+            #[serde(default)]
+            data: Value,
+        }
+
+        let Event { kind, data } = Event::deserialize(deserializer)?;
+        match KnownEvent::parse(&kind, data.clone()) {
+            Some(Ok(event)) => Ok(PipelineEventKind::Known(event)),
+            Some(Err(e)) => {
+                log::warn!("Invalid {} pipeline event: {}", kind, e);
+                Ok(PipelineEventKind::Other { kind, data })
+            }
+            None => Ok(PipelineEventKind::Other { kind, data }),
+        }
+    }
+}
+
+// An event of an `assist_pipeline/run` subscription, sent to the webview as
+// `pipeline-event`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipelineRunEvent {
+    #[serde(flatten)]
+    pub event: PipelineEventKind,
+    pub timestamp: String,
+}
+
+// The data of a stage's start event, merged with that of its end event once
+// the stage is done.
+#[derive(Clone, Debug, Serialize)]
+pub struct StageProgress<S, E> {
+    #[serde(flatten)]
+    pub start: S,
+    #[serde(flatten)]
+    pub end: Option<E>,
+    pub done: bool,
+}
+
+impl<S, E> StageProgress<S, E> {
+    fn start(start: S) -> Self {
+        StageProgress {
+            start,
+            end: None,
+            done: false,
+        }
+    }
+
+    fn end(&mut self, end: E) {
+        self.end = Some(end);
+        self.done = true;
+    }
+}
+
+// The state of a run, built from its events the way
+// `HomeAssistant.processEvent` in src/lib/homeAssistant.ts did.
+#[derive(Clone, Debug, Serialize)]
+pub struct PipelineRun {
+    pub stage: RunStage,
+    pub run: RunStart,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<PipelineError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wake_word: Option<StageProgress<AudioStart, WakeWordEnd>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stt: Option<StageProgress<AudioStart, SttEnd>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intent: Option<StageProgress<IntentStart, IntentEnd>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tts: Option<StageProgress<TtsStart, TtsEnd>>,
+}
+
+impl PipelineRun {
+    fn new(run: RunStart) -> Self {
+        PipelineRun {
+            stage: RunStage::Ready,
+            run,
+            error: None,
+            wake_word: None,
+            stt: None,
+            intent: None,
+            tts: None,
+        }
+    }
+
+    fn process(&mut self, event: KnownEvent) {
+        match event {
+            // Handled before there is a run
+            KnownEvent::RunStart(_) => {}
+            KnownEvent::RunEnd(_) => self.stage = RunStage::Done,
+            KnownEvent::Error(error) => {
+                self.stage = RunStage::Error;
+                self.error = Some(error);
+            }
+            KnownEvent::WakeWordStart(start) => {
+                self.stage = RunStage::WakeWord;
+                self.wake_word = Some(StageProgress::start(start));
+            }
+            KnownEvent::WakeWordEnd(end) => {
+                if let Some(wake_word) = &mut self.wake_word {
+                    wake_word.end(end);
+                }
+            }
+            KnownEvent::SttStart(start) => {
+                self.stage = RunStage::Stt;
+                self.stt = Some(StageProgress::start(start));
+            }
+            KnownEvent::SttEnd(end) => {
+                if let Some(stt) = &mut self.stt {
+                    stt.end(end);
+                }
+            }
+            KnownEvent::IntentStart(start) => {
+                self.stage = RunStage::Intent;
+                self.intent = Some(StageProgress::start(start));
+            }
+            KnownEvent::IntentEnd(end) => {
+                if let Some(intent) = &mut self.intent {
+                    intent.end(end);
+                }
+            }
+            KnownEvent::TtsStart(start) => {
+                self.stage = RunStage::Tts;
+                self.tts = Some(StageProgress::start(start));
+            }
+            KnownEvent::TtsEnd(end) => {
+                if let Some(tts) = &mut self.tts {
+                    tts.end(end);
+                }
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.stage, RunStage::Done | RunStage::Error)
+    }
+}
+
+// Run the pipeline (or the preferred one) on `text` over the app's own
+// connection, continuing the current conversation. Events are sent to the
+// webview as they come in, the run is returned once it has finished.
+pub async fn run_text(
+    app_handle: &tauri::AppHandle,
+    text: &str,
+    pipeline: Option<&str>,
+    end_stage: PipelineStage,
+) -> Result<PipelineRun, WebSocketError> {
+    let client = app_handle.state::<HomeAssistantClient>();
+    let connection = client.connection()?;
+
+    let mut message = json!({
+        "type": "assist_pipeline/run",
+        "start_stage": PipelineStage::Intent,
+        "end_stage": end_stage,
+        "input": { "text": text },
+        "conversation_id": client.conversation_id(),
+    });
+    if let Some(pipeline) = pipeline {
+        message["pipeline"] = json!(pipeline);
+    }
+
+    let mut events = connection.subscribe(message).await?;
+    let mut run: Option<PipelineRun> = None;
+    let mut deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        let event = match tokio::time::timeout_at(deadline, events.next()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(WebSocketError::Timeout(
+                    "Home Assistant did not finish the pipeline run in time".to_string(),
+                ))
+            }
+        };
+        let event: PipelineRunEvent = match serde_json::from_value(event) {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("Invalid pipeline event: {}", e);
+                continue;
+            }
+        };
+        // Without a run, an error is returned rather than emitted, so that
+        // it is only reported once.
+        if let (None, PipelineEventKind::Known(KnownEvent::Error(error))) = (&run, &event.event) {
+            return Err(WebSocketError::Failed {
+                code: error.code.clone(),
+                message: error.message.clone(),
+            });
+        }
+        emit(app_handle, &event);
+
+        let PipelineEventKind::Known(event) = event.event else {
+            continue;
+        };
+        if let KnownEvent::IntentEnd(end) = &event {
+            client.set_conversation_id(end.intent_output.conversation_id.clone());
+        }
+        match &mut run {
+            Some(run) => run.process(event),
+            None => match event {
+                KnownEvent::RunStart(start) => {
+                    let timeout = start
+                        .runner_data
+                        .timeout
+                        .and_then(|timeout| Duration::try_from_secs_f64(timeout).ok())
+                        .unwrap_or(START_TIMEOUT);
+                    deadline = Instant::now() + timeout + TIMEOUT_MARGIN;
+                    run = Some(PipelineRun::new(start));
+                }
+                event => log::warn!("Pipeline event before the run started: {:?}", event),
+            },
+        }
+        if run.as_ref().is_some_and(PipelineRun::is_finished) {
+            break;
+        }
+    }
+
+    match run {
+        Some(run) if run.is_finished() => Ok(run),
+        _ => Err(WebSocketError::Interrupted(
+            "Lost the connection to Home Assistant during the pipeline run".to_string(),
+        )),
+    }
+}
+
+// Like `run_text`, over the REST API for when there is no websocket. Only the
+// conversation agent of the pipeline runs, given by `language` and
+// `agent_id`. Its answer is sent as the events of a run that ends after the
+// intent stage, so the webview doesn't have to tell the two apart.
+pub async fn run_text_rest(
+    app_handle: &tauri::AppHandle,
+    text: &str,
+    pipeline: Option<&str>,
+    language: Option<&str>,
+    agent_id: Option<&str>,
+) -> Result<PipelineRun, HttpError> {
+    let client = app_handle.state::<HomeAssistantClient>();
+    let settings = app_handle.state::<SettingsStore>().effective().settings;
+    let result = conversation::process(
+        settings.home_assistant()?,
+        &settings.network,
+        text,
+        client.conversation_id().as_deref(),
+        language,
+        agent_id,
+    )
+    .await?;
+    client.set_conversation_id(result.conversation_id.clone());
+
+    let language = language.unwrap_or(&result.response.language).to_string();
+    let start = RunStart {
+        pipeline: pipeline.unwrap_or_default().to_string(),
+        language: language.clone(),
+        runner_data: RunnerData {
+            stt_binary_handler_id: None,
+            timeout: Some(conversation::PROCESS_TIMEOUT.as_secs_f64()),
+        },
+    };
+    let events = [
+        KnownEvent::RunStart(start.clone()),
+        KnownEvent::IntentStart(IntentStart {
+            engine: agent_id.unwrap_or(DEFAULT_AGENT).to_string(),
+            language,
+            intent_input: text.to_string(),
+        }),
+        KnownEvent::IntentEnd(IntentEnd {
+            intent_output: result,
+        }),
+        KnownEvent::RunEnd(Value::Null),
+    ];
+
+    let timestamp = humantime::format_rfc3339_micros(SystemTime::now()).to_string();
+    let mut run = PipelineRun::new(start);
+    for event in events {
+        emit(
+            app_handle,
+            &PipelineRunEvent {
+                event: PipelineEventKind::Known(event.clone()),
+                timestamp: timestamp.clone(),
+            },
+        );
+        run.process(event);
+    }
+    Ok(run)
+}
+
+fn emit(app_handle: &tauri::AppHandle, event: &PipelineRunEvent) {
+    log::debug!("Pipeline event: {:?}", event);
+    if let Err(e) = app_handle.emit_all("pipeline-event", event) {
+        log::warn!("Could not emit pipeline-event event: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: &str, data: Value) -> KnownEvent {
+        KnownEvent::parse(kind, data).unwrap().unwrap()
+    }
+
+    fn start() -> PipelineRun {
+        let data = json!({
+            "pipeline": "01hx",
+            "language": "en",
+            "runner_data": { "stt_binary_handler_id": null, "timeout": 300 },
+        });
+        let KnownEvent::RunStart(start) = event("run-start", data) else {
+            unreachable!()
+        };
+        PipelineRun::new(start)
+    }
+
+    fn intent_start() -> KnownEvent {
+        event(
+            "intent-start",
+            json!({
+                "engine": "conversation.home_assistant",
+                "language": "en",
+                "intent_input": "turn on the kitchen light",
+            }),
+        )
+    }
+
+    fn intent_end() -> KnownEvent {
+        event(
+            "intent-end",
+            json!({
+                "intent_output": {
+                    "conversation_id": "01J",
+                    "response": {
+                        "response_type": "action_done",
+                        "language": "en",
+                        "speech": { "plain": { "speech": "Turned on the light" } },
+                    },
+                },
+            }),
+        )
+    }
+
+    #[test]
+    fn follows_the_stages() {
+        let mut run = start();
+        assert_eq!(run.stage, RunStage::Ready);
+
+        run.process(intent_start());
+        assert_eq!(run.stage, RunStage::Intent);
+        assert!(!run.intent.as_ref().unwrap().done);
+
+        run.process(intent_end());
+        run.process(event(
+            "tts-start",
+            json!({
+                "engine": "tts.piper",
+                "language": "en_US",
+                "voice": null,
+                "tts_input": "Turned on the light",
+            }),
+        ));
+        assert_eq!(run.stage, RunStage::Tts);
+        run.process(event(
+            "tts-end",
+            json!({ "tts_output": { "url": "/api/tts_proxy/abc.mp3", "mime_type": "audio/mpeg" } }),
+        ));
+        assert!(!run.is_finished());
+
+        run.process(event("run-end", Value::Null));
+        assert_eq!(run.stage, RunStage::Done);
+        assert!(run.is_finished());
+        assert!(run.error.is_none());
+
+        // Each stage is sent to the webview with its start and end merged.
+        let run = serde_json::to_value(&run).unwrap();
+        assert_eq!(run["stage"], "done");
+        assert_eq!(run["intent"]["intent_input"], "turn on the kitchen light");
+        assert_eq!(run["intent"]["intent_output"]["conversation_id"], "01J");
+        assert_eq!(run["intent"]["done"], true);
+        assert_eq!(run["tts"]["tts_output"]["url"], "/api/tts_proxy/abc.mp3");
+        assert!(run.get("stt").is_none());
+    }
+
+    #[test]
+    fn finishes_on_errors() {
+        let mut run = start();
+        run.process(intent_start());
+        run.process(event(
+            "error",
+            json!({ "code": "intent-failed", "message": "Unexpected error" }),
+        ));
+
+        assert_eq!(run.stage, RunStage::Error);
+        assert!(run.is_finished());
+        assert_eq!(run.error.as_ref().unwrap().code, "intent-failed");
+        assert!(!run.intent.as_ref().unwrap().done);
+    }
+
+    #[test]
+    fn ignores_ends_of_stages_that_did_not_start() {
+        let mut run = start();
+        run.process(intent_end());
+        assert_eq!(run.stage, RunStage::Ready);
+        assert!(run.intent.is_none());
+    }
+
+    #[test]
+    fn passes_on_unknown_events() {
+        let event: PipelineRunEvent = serde_json::from_value(json!({
+            "type": "stt-vad-start",
+            "data": { "timestamp": 1200 },
+            "timestamp": "2024-06-01T10:00:00+00:00",
+        }))
+        .unwrap();
+        match &event.event {
+            PipelineEventKind::Other { kind, data } => {
+                assert_eq!(kind, "stt-vad-start");
+                assert_eq!(data["timestamp"], 1200);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["type"],
+            "stt-vad-start"
+        );
+    }
+
+    #[test]
+    fn passes_on_known_events_that_do_not_parse() {
+        let event: PipelineRunEvent = serde_json::from_value(json!({
+            "type": "intent-end",
+            "data": { "intent_output": "not an object" },
+            "timestamp": "2024-06-01T10:00:00+00:00",
+        }))
+        .unwrap();
+        match &event.event {
+            PipelineEventKind::Other { kind, data } => {
+                assert_eq!(kind, "intent-end");
+                assert_eq!(data["intent_output"], "not an object");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_events_without_optional_fields() {
+        let KnownEvent::RunStart(start) = event(
+            "run-start",
+            json!({
+                "pipeline": "01hx",
+                "language": "en",
+                "runner_data": { "stt_binary_handler_id": 1 },
+            }),
+        ) else {
+            unreachable!()
+        };
+        assert!(start.runner_data.timeout.is_none());
+
+        let KnownEvent::SttStart(start) = event(
+            "stt-start",
+            json!({ "engine": "stt.whisper", "metadata": { "language": "en" } }),
+        ) else {
+            unreachable!()
+        };
+        assert_eq!(start.metadata.language, "en");
+        assert_eq!(start.metadata.sample_rate, 0);
+
+        let KnownEvent::IntentEnd(end) = event(
+            "intent-end",
+            json!({
+                "intent_output": {
+                    "conversation_id": null,
+                    "response": { "response_type": "action_done", "speech": {} },
+                },
+            }),
+        ) else {
+            unreachable!()
+        };
+        assert!(end.intent_output.response.language.is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use super::{Connection, WebSocketError};
+use crate::http;
+use crate::settings::{HomeAssistantSettings, NetworkSettings};
+
+// How long each request of the test may take.
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Why a connection test failed, for the settings page to point at the
+// setting that needs fixing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TestFailure {
+    // The host name could not be resolved.
+    Dns,
+    // Nothing accepted the TCP connection.
+    Tcp,
+    // The server's certificate could not be verified.
+    Tls,
+    // 401, or `auth_invalid` on the websocket: the access token is wrong.
+    Unauthorized,
+    // 403: usually the IP was banned after too many failed logins.
+    Forbidden,
+    Timeout,
+    // Anything else, e.g. a proxy refusing the websocket upgrade.
+    Other,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ConnectionTest {
+    Passed {
+        ha_version: String,
+        user_name: String,
+        is_admin: bool,
+        // Round trip of a websocket ping.
+        latency_ms: u64,
+    },
+    Failed {
+        failure: TestFailure,
+        message: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct ApiConfig {
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct CurrentUser {
+    name: String,
+    is_admin: bool,
+}
+
+// Check settings that haven't been saved yet the way the app will use them:
+// the REST API with the access token, then the websocket.
+pub async fn test_connection(
+    home_assistant: &HomeAssistantSettings,
+    network: &NetworkSettings,
+) -> ConnectionTest {
+    match run(home_assistant, network).await {
+        Ok(test) => test,
+        Err((failure, message)) => {
+            log::info!("Connection test failed ({:?}): {}", failure, message);
+            ConnectionTest::Failed { failure, message }
+        }
+    }
+}
+
+async fn run(
+    home_assistant: &HomeAssistantSettings,
+    network: &NetworkSettings,
+) -> Result<ConnectionTest, (TestFailure, String)> {
+    let client =
+        http::client(network, TEST_TIMEOUT).map_err(|message| (TestFailure::Other, message))?;
+
+    get(&client, home_assistant, "/api/").await?;
+    let config: ApiConfig = parse(
+        get(&client, home_assistant, "/api/config").await?,
+        "/api/config",
+    )?;
+
+    // The REST API works, so anything failing from here on is down to the
+    // websocket, e.g. a reverse proxy that doesn't pass on the upgrade.
+    let websocket_failure = |e: WebSocketError| {
+        let failure = match e {
+            WebSocketError::Dns(_) => TestFailure::Dns,
+            WebSocketError::Tcp(_) => TestFailure::Tcp,
+            WebSocketError::Tls(_) => TestFailure::Tls,
+            WebSocketError::Timeout(_) => TestFailure::Timeout,
+            WebSocketError::Auth(_) => TestFailure::Unauthorized,
+            _ => TestFailure::Other,
+        };
+        (
+            failure,
+            format!("The REST API works, but the websocket failed: {}", e),
+        )
+    };
+
+    let connection = Connection::open(home_assistant, network)
+        .await
+        .map_err(websocket_failure)?;
+    let user = call(&connection, json!({ "type": "auth/current_user" }))
+        .await
+        .map_err(websocket_failure)?;
+    let user: CurrentUser = parse(user, "auth/current_user")?;
+
+    let started = Instant::now();
+    call(&connection, json!({ "type": "ping" }))
+        .await
+        .map_err(websocket_failure)?;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    Ok(ConnectionTest::Passed {
+        ha_version: config.version,
+        user_name: user.name,
+        is_admin: user.is_admin,
+        latency_ms,
+    })
+}
+
+async fn get(
+    client: &reqwest::Client,
+    home_assistant: &HomeAssistantSettings,
+    path: &str,
+) -> Result<Value, (TestFailure, String)> {
+    let url = home_assistant
+        .url(path)
+        .map_err(|e| (TestFailure::Other, e.to_string()))?;
+    let response = client
+        .get(url.clone())
+        .bearer_auth(&home_assistant.access_token)
+        .send()
+        .await
+        .map_err(|e| {
+            let failure = if e.is_timeout() {
+                TestFailure::Timeout
+            } else if http::is_certificate_error(&e) {
+                TestFailure::Tls
+            } else if http::is_dns_error(&e) {
+                TestFailure::Dns
+            } else if e.is_connect() {
+                TestFailure::Tcp
+            } else {
+                TestFailure::Other
+            };
+            (
+                failure,
+                format!("Could not reach {}: {}", url, http::root_cause(&e)),
+            )
+        })?;
+
+    let status = response.status();
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED => Err((
+            TestFailure::Unauthorized,
+            format!("Home Assistant rejected the access token ({})", status),
+        )),
+        reqwest::StatusCode::FORBIDDEN => Err((
+            TestFailure::Forbidden,
+            format!(
+                "Home Assistant refused access ({}). After too many failed logins it bans the \
+                 IP, which can be undone by removing it from ip_bans.yaml",
+                status
+            ),
+        )),
+        status if !status.is_success() => Err((
+            TestFailure::Other,
+            format!(
+                "{} returned {}, check the URL includes any reverse proxy path",
+                url, status
+            ),
+        )),
+        _ => response.json().await.map_err(|e| {
+            (
+                TestFailure::Other,
+                format!("{} did not return JSON, is it Home Assistant? {}", url, e),
+            )
+        }),
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: Value, source: &str) -> Result<T, (TestFailure, String)> {
+    serde_json::from_value(value).map_err(|e| {
+        (
+            TestFailure::Other,
+            format!("Unexpected response to {}: {}", source, e),
+        )
+    })
+}
+
+// A websocket call that gives up after the test timeout.
+async fn call(connection: &Connection, message: Value) -> Result<Value, WebSocketError> {
+    tokio::time::timeout(TEST_TIMEOUT, connection.call(message))
+        .await
+        .map_err(|_| {
+            WebSocketError::Timeout("Home Assistant did not respond in time".to_string())
+        })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    const TOKEN: &str = "token";
+
+    // Status and body of the REST responses by path.
+    type Responses = &'static [(&'static str, u16, &'static str)];
+
+    const WORKING: Responses = &[
+        ("/api/", 200, r#"{ "message": "API running." }"#),
+        ("/api/config", 200, r#"{ "version": "2024.6.0" }"#),
+    ];
+
+    // A stand-in for Home Assistant answering REST requests from `responses`
+    // and the websocket like a working server.
+    async fn serve(responses: Responses) -> HomeAssistantSettings {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(respond(stream, responses));
+            }
+        });
+
+        HomeAssistantSettings {
+            access_token: TOKEN.to_string(),
+            base_url: base_url.parse().unwrap(),
+            ..HomeAssistantSettings::default()
+        }
+    }
+
+    async fn respond(mut stream: TcpStream, responses: Responses) {
+        // Peek, so the websocket handshake can still read the request.
+        let mut buffer = [0; 4096];
+        let (head, length) = loop {
+            let read = stream.peek(&mut buffer).await.unwrap();
+            let text = String::from_utf8_lossy(&buffer[..read]).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                break (text[..end].to_string(), end + 4);
+            }
+            tokio::task::yield_now().await;
+        };
+        let path = head.split_whitespace().nth(1).unwrap_or_default();
+
+        if path == "/api/websocket" {
+            let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            websocket_session(socket).await;
+            return;
+        }
+
+        stream.read_exact(&mut buffer[..length]).await.unwrap();
+        let (status, body) = responses
+            .iter()
+            .find(|(response_path, _, _)| *response_path == path)
+            .map_or((404, "Not found"), |(_, status, body)| (*status, *body));
+        let response = format!(
+            "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    async fn websocket_session(mut socket: WebSocketStream<TcpStream>) {
+        let send = |message: Value| Message::Text(message.to_string());
+        let auth_required = json!({ "type": "auth_required", "ha_version": "2024.6.0" });
+        socket.send(send(auth_required)).await.unwrap();
+
+        while let Some(Ok(message)) = socket.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            let reply = match message["type"].as_str() {
+                Some("auth") if message["access_token"] == TOKEN => json!({ "type": "auth_ok" }),
+                Some("auth") => {
+                    json!({ "type": "auth_invalid", "message": "Invalid access token" })
+                }
+                Some("auth/current_user") => json!({
+                    "id": message["id"],
+                    "type": "result",
+                    "success": true,
+                    "result": { "name": "Ada", "is_admin": true },
+                }),
+                Some("ping") => json!({ "id": message["id"], "type": "pong" }),
+                _ => continue,
+            };
+            socket.send(send(reply)).await.unwrap();
+        }
+    }
+
+    async fn test(home_assistant: &HomeAssistantSettings) -> ConnectionTest {
+        test_connection(home_assistant, &NetworkSettings::default()).await
+    }
+
+    fn failure(test: ConnectionTest) -> (TestFailure, String) {
+        match test {
+            ConnectionTest::Failed { failure, message } => (failure, message),
+            passed => panic!("unexpected {:?}", passed),
+        }
+    }
+
+    #[tokio::test]
+    async fn passes() {
+        let home_assistant = serve(WORKING).await;
+        match test(&home_assistant).await {
+            ConnectionTest::Passed {
+                ha_version,
+                user_name,
+                is_admin,
+                ..
+            } => {
+                assert_eq!(ha_version, "2024.6.0");
+                assert_eq!(user_name, "Ada");
+                assert!(is_admin);
+            }
+            failed => panic!("unexpected {:?}", failed),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_rejected_access_tokens() {
+        let home_assistant = serve(&[("/api/", 401, "401: Unauthorized")]).await;
+        let (failure, message) = failure(test(&home_assistant).await);
+        assert_eq!(failure, TestFailure::Unauthorized);
+        assert!(message.contains("rejected the access token"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn fails_on_banned_ips() {
+        let home_assistant = serve(&[("/api/", 403, "403: Forbidden")]).await;
+        let (failure, message) = failure(test(&home_assistant).await);
+        assert_eq!(failure, TestFailure::Forbidden);
+        assert!(message.contains("ip_bans.yaml"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn fails_on_responses_that_are_not_json() {
+        let home_assistant = serve(&[("/api/", 200, "<html>Router login</html>")]).await;
+        let (failure, message) = failure(test(&home_assistant).await);
+        assert_eq!(failure, TestFailure::Other);
+        assert!(message.contains("did not return JSON"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn fails_on_refused_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let home_assistant = HomeAssistantSettings {
+            access_token: TOKEN.to_string(),
+            base_url: base_url.parse().unwrap(),
+            ..HomeAssistantSettings::default()
+        };
+
+        let (failure, message) = failure(test(&home_assistant).await);
+        assert_eq!(failure, TestFailure::Tcp);
+        assert!(message.starts_with("Could not reach"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn fails_on_rejected_websocket_logins() {
+        let mut home_assistant = serve(WORKING).await;
+        // The REST API doesn't check the token in this stand-in, the websocket does.
+        home_assistant.access_token = "wrong".to_string();
+        let (failure, message) = failure(test(&home_assistant).await);
+        assert_eq!(failure, TestFailure::Unauthorized);
+        assert!(
+            message.starts_with("The REST API works, but the websocket failed"),
+            "{}",
+            message
+        );
+    }
+}
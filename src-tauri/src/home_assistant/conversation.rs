@@ -0,0 +1,223 @@
+use serde::Serialize;
+use std::time::Duration;
+
+use super::assist::ConversationResult;
+use crate::http::{self, HttpError};
+use crate::settings::{HomeAssistantSettings, NetworkSettings};
+
+// Agents backed by a language model can take a while to answer.
+pub const PROCESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize)]
+struct ProcessRequest<'a> {
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_id: Option<&'a str>,
+}
+
+// Send text to a conversation agent over `POST /api/conversation/process`,
+// which works without a websocket. Without `agent_id` or `language` Home
+// Assistant uses its default agent and language.
+pub async fn process(
+    home_assistant: &HomeAssistantSettings,
+    network: &NetworkSettings,
+    text: &str,
+    conversation_id: Option<&str>,
+    language: Option<&str>,
+    agent_id: Option<&str>,
+) -> Result<ConversationResult, HttpError> {
+    let client = http::client(network, PROCESS_TIMEOUT)?;
+    let request = client
+        .post(home_assistant.url("/api/conversation/process")?)
+        .bearer_auth(&home_assistant.access_token)
+        .json(&ProcessRequest {
+            text,
+            conversation_id,
+            language,
+            agent_id,
+        });
+
+    let response = http::send(request, "Home Assistant server").await?;
+    Ok(response
+        .json()
+        .await
+        .map_err(|e| format!("Invalid response from Home Assistant: {}", e))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use url::Url;
+
+    // What the stand-in server received.
+    struct Request {
+        head: String,
+        body: Value,
+    }
+
+    // A stand-in for Home Assistant behind a reverse proxy at `/ha/`,
+    // answering a single request with `status` and `body`.
+    async fn serve(
+        status: u16,
+        body: &'static str,
+    ) -> (HomeAssistantSettings, tokio::task::JoinHandle<Request>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let home_assistant = HomeAssistantSettings {
+            access_token: "token".to_string(),
+            base_url: Url::parse(&format!("http://{}/ha/", listener.local_addr().unwrap()))
+                .unwrap(),
+            ..HomeAssistantSettings::default()
+        };
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data = Vec::new();
+            let mut buffer = [0; 4096];
+            let (head, length) = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                data.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&data);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let head = text[..end].to_string();
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|value| value.parse().unwrap())
+                        })
+                        .unwrap();
+                    data.drain(..end + 4);
+                    break (head, length);
+                }
+            };
+            while data.len() < length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                data.extend_from_slice(&buffer[..read]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            Request {
+                head,
+                body: serde_json::from_slice(&data).unwrap(),
+            }
+        });
+        (home_assistant, server)
+    }
+
+    #[tokio::test]
+    async fn processes_text() {
+        let (home_assistant, server) = serve(
+            200,
+            r#"{
+                "conversation_id": "01hy",
+                "response": {
+                    "response_type": "action_done",
+                    "language": "en",
+                    "speech": { "plain": { "speech": "Turned on the lights", "extra_data": null } },
+                    "data": { "targets": [], "success": [], "failed": [] }
+                }
+            }"#,
+        )
+        .await;
+
+        let result = process(
+            &home_assistant,
+            &NetworkSettings::default(),
+            "Turn on the lights",
+            Some("01hx"),
+            Some("en"),
+            Some("conversation.chatgpt"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.conversation_id.as_deref(), Some("01hy"));
+        assert_eq!(result.response.response_type, "action_done");
+        assert_eq!(
+            result.response.speech.unwrap().plain.unwrap().speech,
+            "Turned on the lights"
+        );
+
+        let request = server.await.unwrap();
+        assert!(request
+            .head
+            .starts_with("POST /ha/api/conversation/process HTTP/1.1"));
+        assert!(request.head.contains("authorization: Bearer token"));
+        assert_eq!(
+            request.body,
+            json!({
+                "text": "Turn on the lights",
+                "conversation_id": "01hx",
+                "language": "en",
+                "agent_id": "conversation.chatgpt",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_defaults() {
+        let (home_assistant, server) = serve(
+            200,
+            r#"{
+                "conversation_id": null,
+                "response": { "response_type": "error", "language": "en", "speech": null }
+            }"#,
+        )
+        .await;
+
+        let result = process(
+            &home_assistant,
+            &NetworkSettings::default(),
+            "Hello",
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.response.response_type, "error");
+
+        let request = server.await.unwrap();
+        assert_eq!(request.body, json!({ "text": "Hello" }));
+    }
+
+    #[tokio::test]
+    async fn reports_errors() {
+        let (home_assistant, _server) = serve(401, "").await;
+        let error = process(
+            &home_assistant,
+            &NetworkSettings::default(),
+            "Hello",
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, HttpError::Auth(_)));
+
+        let (home_assistant, _server) = serve(200, "<html>").await;
+        let error = process(
+            &home_assistant,
+            &NetworkSettings::default(),
+            "Hello",
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Invalid response"));
+    }
+}
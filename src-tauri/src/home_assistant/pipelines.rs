@@ -0,0 +1,146 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::{Connection, WebSocketError};
+
+// Mirrors `AssistPipelineMutableParams` in src/types/homeAssistantAssist.ts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssistPipelineMutableParams {
+    pub name: String,
+    pub language: String,
+    pub conversation_engine: String,
+    pub conversation_language: Option<String>,
+    pub stt_engine: Option<String>,
+    pub stt_language: Option<String>,
+    pub tts_engine: Option<String>,
+    pub tts_language: Option<String>,
+    pub tts_voice: Option<String>,
+    pub wake_word_entity: Option<String>,
+    pub wake_word_id: Option<String>,
+    // Fields added by newer versions of Home Assistant, e.g.
+    // `prefer_local_intents`. Updates replace the whole pipeline, so these
+    // are sent back rather than reset.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Mirrors `AssistPipeline` in src/types/homeAssistantAssist.ts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssistPipeline {
+    pub id: String,
+    #[serde(flatten)]
+    pub params: AssistPipelineMutableParams,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipelineList {
+    pub pipelines: Vec<AssistPipeline>,
+    pub preferred_pipeline: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LanguageList {
+    languages: Vec<String>,
+}
+
+pub async fn list(connection: &Connection) -> Result<PipelineList, WebSocketError> {
+    call(
+        connection,
+        json!({ "type": "assist_pipeline/pipeline/list" }),
+    )
+    .await
+}
+
+// The pipeline with `id`, or the preferred pipeline.
+pub async fn get(
+    connection: &Connection,
+    id: Option<&str>,
+) -> Result<AssistPipeline, WebSocketError> {
+    let mut message = json!({ "type": "assist_pipeline/pipeline/get" });
+    if let Some(id) = id {
+        message["pipeline_id"] = json!(id);
+    }
+    call(connection, message).await
+}
+
+pub async fn create(
+    connection: &Connection,
+    params: &AssistPipelineMutableParams,
+) -> Result<AssistPipeline, WebSocketError> {
+    let mut message = json!(params);
+    message["type"] = json!("assist_pipeline/pipeline/create");
+    call(connection, message).await
+}
+
+pub async fn update(
+    connection: &Connection,
+    id: &str,
+    params: &AssistPipelineMutableParams,
+) -> Result<AssistPipeline, WebSocketError> {
+    let mut message = json!(params);
+    message["type"] = json!("assist_pipeline/pipeline/update");
+    message["pipeline_id"] = json!(id);
+    call(connection, message).await
+}
+
+pub async fn set_preferred(connection: &Connection, id: &str) -> Result<(), WebSocketError> {
+    connection
+        .call(json!({ "type": "assist_pipeline/pipeline/set_preferred", "pipeline_id": id }))
+        .await
+        .map(|_| ())
+}
+
+pub async fn delete(connection: &Connection, id: &str) -> Result<(), WebSocketError> {
+    connection
+        .call(json!({ "type": "assist_pipeline/pipeline/delete", "pipeline_id": id }))
+        .await
+        .map(|_| ())
+}
+
+// Languages that pipelines can be set up for.
+pub async fn languages(connection: &Connection) -> Result<Vec<String>, WebSocketError> {
+    let list: LanguageList = call(
+        connection,
+        json!({ "type": "assist_pipeline/language/list" }),
+    )
+    .await?;
+    Ok(list.languages)
+}
+
+async fn call<T: DeserializeOwned>(
+    connection: &Connection,
+    message: Value,
+) -> Result<T, WebSocketError> {
+    let kind = message["type"].clone();
+    let result = connection.call(message).await?;
+    serde_json::from_value(result)
+        .map_err(|e| WebSocketError::Protocol(format!("Unexpected result of {}: {}", kind, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_fields_it_does_not_know() {
+        let pipeline = json!({
+            "id": "01hx",
+            "name": "Home Assistant",
+            "language": "en",
+            "conversation_engine": "conversation.home_assistant",
+            "conversation_language": "en",
+            "stt_engine": null,
+            "stt_language": null,
+            "tts_engine": "tts.piper",
+            "tts_language": "en_US",
+            "tts_voice": null,
+            "wake_word_entity": null,
+            "wake_word_id": null,
+            "prefer_local_intents": true,
+        });
+        let parsed: AssistPipeline = serde_json::from_value(pipeline.clone()).unwrap();
+        assert_eq!(parsed.params.extra.len(), 1);
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), pipeline);
+    }
+}
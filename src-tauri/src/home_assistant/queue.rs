@@ -0,0 +1,472 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::Manager;
+
+use super::{run_text, PipelineRun, PipelineStage, WebSocketError};
+use crate::settings::SettingsStore;
+
+// How long a queued command is kept for Home Assistant to come back.
+const EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+// Commands like "open the gate" shouldn't run long after they were given, so
+// they are dropped if Home Assistant isn't back after a restart's worth.
+const TIME_SENSITIVE_EXPIRY: Duration = Duration::from_secs(2 * 60);
+
+// A text command given while Home Assistant couldn't be reached.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedCommand {
+    pub id: u64,
+    pub text: String,
+    // The profile it was given for, as it is only replayed to that server.
+    pub profile: String,
+    pub pipeline: Option<String>,
+    pub time_sensitive: bool,
+    // Seconds since the Unix epoch.
+    pub queued_at: u64,
+    pub expires_at: u64,
+}
+
+// What became of a queued command, sent to the webview as a `queued-command`
+// event and shown as a notification.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum ReplayOutcome {
+    Sent { reply: Option<String> },
+    Failed { message: String },
+    // Home Assistant wasn't back in time.
+    Expired,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReplayResult {
+    pub command: QueuedCommand,
+    #[serde(flatten)]
+    pub outcome: ReplayOutcome,
+}
+
+// Commands waiting for the connection to come back, kept in a file in the
+// app data directory so they survive a restart of the app.
+pub struct CommandQueue {
+    path: PathBuf,
+    commands: Mutex<Vec<QueuedCommand>>,
+    // Held while replaying, so reconnecting again meanwhile doesn't run
+    // commands twice.
+    replaying: tokio::sync::Mutex<()>,
+}
+
+impl CommandQueue {
+    pub fn load(path: PathBuf) -> Self {
+        let commands = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid command queue {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::warn!("Could not read command queue {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+
+        CommandQueue {
+            path,
+            commands: Mutex::new(commands),
+            replaying: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn commands(&self) -> Vec<QueuedCommand> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn push(
+        &self,
+        text: &str,
+        profile: &str,
+        pipeline: Option<&str>,
+        time_sensitive: bool,
+    ) -> Result<QueuedCommand, String> {
+        let now = now();
+        let expiry = if time_sensitive {
+            TIME_SENSITIVE_EXPIRY
+        } else {
+            EXPIRY
+        };
+
+        let mut commands = self.commands.lock().unwrap();
+        // Unique across restarts of the app, unless the clock goes back.
+        let id = commands.last().map_or(0, |command| command.id + 1).max(now);
+        let command = QueuedCommand {
+            id,
+            text: text.to_string(),
+            profile: profile.to_string(),
+            pipeline: pipeline.map(str::to_string),
+            time_sensitive,
+            queued_at: now,
+            expires_at: now + expiry.as_secs(),
+        };
+        commands.push(command.clone());
+        self.save(&commands)?;
+        log::info!("Queued command {} until Home Assistant is back", command.id);
+        Ok(command)
+    }
+
+    fn remove(&self, id: u64) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.retain(|command| command.id != id);
+        if let Err(e) = self.save(&commands) {
+            log::warn!("Could not save the command queue: {}", e);
+        }
+    }
+
+    fn save(&self, commands: &[QueuedCommand]) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let contents = serde_json::to_vec_pretty(commands).map_err(|e| e.to_string())?;
+        crate::settings::write_atomically(&self.path, &contents)
+            .map_err(|e| format!("Could not save {}: {}", self.path.display(), e))
+    }
+}
+
+// Run the commands queued for the active profile in the order they were
+// given, once the connection is back. Expired commands are dropped. If the
+// connection drops again, the rest wait for the next reconnect.
+pub async fn replay(app_handle: tauri::AppHandle) {
+    let Some(queue) = app_handle.try_state::<CommandQueue>() else {
+        return;
+    };
+    let Ok(_replaying) = queue.replaying.try_lock() else {
+        return;
+    };
+    let profile = app_handle
+        .state::<SettingsStore>()
+        .read(|settings| settings.active_profile.clone());
+
+    let done = |result: ReplayResult| {
+        queue.remove(result.command.id);
+        notify(&app_handle, result);
+    };
+    let (due, expired) = select(queue.commands(), &profile, now());
+    for command in expired {
+        done(ReplayResult {
+            command,
+            outcome: ReplayOutcome::Expired,
+        });
+    }
+    run_in_order(
+        due,
+        |command| {
+            let app_handle = app_handle.clone();
+            async move {
+                log::info!("Replaying queued command {}", command.id);
+                // Spoken replies would be out of place this late, so the run
+                // ends with the conversation agent's answer.
+                run_text(
+                    &app_handle,
+                    &command.text,
+                    command.pipeline.as_deref(),
+                    PipelineStage::Intent,
+                )
+                .await
+            }
+        },
+        done,
+    )
+    .await;
+}
+
+// The commands to replay for `profile`, in the order they were given, and
+// those of any profile that expired by `now`. Commands for other profiles
+// wait until theirs is active.
+fn select(
+    commands: Vec<QueuedCommand>,
+    profile: &str,
+    now: u64,
+) -> (Vec<QueuedCommand>, Vec<QueuedCommand>) {
+    let (expired, waiting): (Vec<_>, Vec<_>) = commands
+        .into_iter()
+        .partition(|command| command.expires_at <= now);
+    let due = waiting
+        .into_iter()
+        .filter(|command| command.profile == profile)
+        .collect();
+    (due, expired)
+}
+
+// Run `commands` one after the other and pass what became of each to `done`.
+// Stops at a command that could not be started for lack of a connection,
+// which stays queued with the ones after it.
+async fn run_in_order<Run, Fut>(
+    commands: Vec<QueuedCommand>,
+    mut run: Run,
+    mut done: impl FnMut(ReplayResult),
+) where
+    Run: FnMut(QueuedCommand) -> Fut,
+    Fut: Future<Output = Result<PipelineRun, WebSocketError>>,
+{
+    for command in commands {
+        let outcome = match run(command.clone()).await {
+            Ok(run) => match run.error {
+                Some(error) => ReplayOutcome::Failed {
+                    message: error.message,
+                },
+                None => ReplayOutcome::Sent {
+                    reply: run
+                        .intent
+                        .and_then(|intent| intent.end)
+                        .and_then(|end| end.intent_output.response.speech)
+                        .and_then(|speech| speech.plain)
+                        .map(|plain| plain.speech),
+                },
+            },
+            // Only a command that never started stays queued, one that was
+            // interrupted may have run and is reported instead.
+            Err(WebSocketError::Closed(message)) => {
+                log::warn!("Lost the connection while replaying commands: {}", message);
+                return;
+            }
+            Err(e) => ReplayOutcome::Failed {
+                message: e.to_string(),
+            },
+        };
+        done(ReplayResult { command, outcome });
+    }
+}
+
+fn notify(app_handle: &tauri::AppHandle, result: ReplayResult) {
+    let text = &result.command.text;
+    let (title, body) = match &result.outcome {
+        ReplayOutcome::Sent { reply } => (
+            format!("Sent \"{}\"", text),
+            reply.clone().unwrap_or_else(|| "Done".to_string()),
+        ),
+        ReplayOutcome::Failed { message } => {
+            (format!("Could not send \"{}\"", text), message.clone())
+        }
+        ReplayOutcome::Expired => (
+            format!("Dropped \"{}\"", text),
+            "Home Assistant was not back in time".to_string(),
+        ),
+    };
+    log::info!("{}: {}", title, body);
+
+    let identifier = app_handle.config().tauri.bundle.identifier.clone();
+    if let Err(e) = tauri::api::notification::Notification::new(identifier)
+        .title(title)
+        .body(body)
+        .show()
+    {
+        log::warn!("Could not show a notification: {}", e);
+    }
+    if let Err(e) = app_handle.emit_all("queued-command", result) {
+        log::warn!("Could not emit queued-command event: {}", e);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::assist::{RunStage, RunStart, RunnerData};
+    use super::*;
+
+    #[test]
+    fn keeps_commands_in_order_across_restarts() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data").join("queue.json");
+        let queue = CommandQueue::load(path.clone());
+        assert!(queue.commands().is_empty());
+
+        let first = queue
+            .push("Turn on the lights", "default", None, false)
+            .unwrap();
+        let second = queue
+            .push("Open the gate", "cabin", Some("01hx"), true)
+            .unwrap();
+        assert!(second.id > first.id);
+        assert_eq!(first.expires_at - first.queued_at, EXPIRY.as_secs());
+        assert_eq!(
+            second.expires_at - second.queued_at,
+            TIME_SENSITIVE_EXPIRY.as_secs()
+        );
+
+        let queue = CommandQueue::load(path.clone());
+        let commands = queue.commands();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].text, "Turn on the lights");
+        assert_eq!(commands[1].profile, "cabin");
+        assert_eq!(commands[1].pipeline.as_deref(), Some("01hx"));
+        assert!(commands[1].time_sensitive);
+
+        queue.remove(first.id);
+        let commands = CommandQueue::load(path).commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].id, second.id);
+    }
+
+    fn command(id: u64, profile: &str, time_sensitive: bool) -> QueuedCommand {
+        let expiry = if time_sensitive {
+            TIME_SENSITIVE_EXPIRY
+        } else {
+            EXPIRY
+        };
+        QueuedCommand {
+            id,
+            text: format!("Command {}", id),
+            profile: profile.to_string(),
+            pipeline: None,
+            time_sensitive,
+            queued_at: 1000,
+            expires_at: 1000 + expiry.as_secs(),
+        }
+    }
+
+    fn ids(commands: &[QueuedCommand]) -> Vec<u64> {
+        commands.iter().map(|command| command.id).collect()
+    }
+
+    #[test]
+    fn replays_commands_of_the_active_profile_in_order() {
+        let commands = vec![
+            command(1, "default", false),
+            command(2, "cabin", false),
+            command(3, "default", true),
+            command(4, "default", false),
+        ];
+        let (due, expired) = select(commands, "default", 1010);
+        assert_eq!(ids(&due), [1, 3, 4]);
+        assert!(expired.is_empty());
+    }
+
+    #[test]
+    fn drops_expired_commands_of_any_profile() {
+        let commands = vec![
+            command(1, "default", false),
+            command(2, "cabin", true),
+            command(3, "default", true),
+        ];
+        // Past the expiry of time sensitive commands only.
+        let now = 1000 + TIME_SENSITIVE_EXPIRY.as_secs();
+        let (due, expired) = select(commands.clone(), "default", now);
+        assert_eq!(ids(&due), [1]);
+        assert_eq!(ids(&expired), [2, 3]);
+
+        let (due, expired) = select(commands, "default", 1000 + EXPIRY.as_secs());
+        assert!(due.is_empty());
+        assert_eq!(ids(&expired), [1, 2, 3]);
+    }
+
+    fn finished_run() -> PipelineRun {
+        PipelineRun {
+            stage: RunStage::Done,
+            run: RunStart {
+                pipeline: "01hx".to_string(),
+                language: "en".to_string(),
+                runner_data: RunnerData {
+                    stt_binary_handler_id: None,
+                    timeout: None,
+                },
+            },
+            error: None,
+            wake_word: None,
+            stt: None,
+            intent: None,
+            tts: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_connection_is_lost() {
+        let commands = vec![
+            command(1, "default", false),
+            command(2, "default", false),
+            command(3, "default", false),
+            command(4, "default", false),
+        ];
+        let mut ran = Vec::new();
+        let mut results = Vec::new();
+        run_in_order(
+            commands,
+            |command| {
+                ran.push(command.id);
+                async move {
+                    match command.id {
+                        1 => Ok(finished_run()),
+                        2 => Err(WebSocketError::Failed {
+                            code: "intent-failed".to_string(),
+                            message: "Unexpected error".to_string(),
+                        }),
+                        _ => Err(WebSocketError::Closed("Connection closed".to_string())),
+                    }
+                }
+            },
+            |result| results.push((result.command.id, result.outcome)),
+        )
+        .await;
+
+        assert_eq!(ran, [1, 2, 3]);
+        assert_eq!(
+            results,
+            [
+                (1, ReplayOutcome::Sent { reply: None }),
+                (
+                    2,
+                    ReplayOutcome::Failed {
+                        message: "Unexpected error (intent-failed)".to_string()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_commands_interrupted_during_the_run() {
+        let commands = vec![command(1, "default", false), command(2, "default", false)];
+        let mut results = Vec::new();
+        run_in_order(
+            commands,
+            |command| async move {
+                match command.id {
+                    1 => Err(WebSocketError::Interrupted(
+                        "Lost the connection to Home Assistant during the pipeline run".to_string(),
+                    )),
+                    _ => Err(WebSocketError::Closed("Connection closed".to_string())),
+                }
+            },
+            |result| results.push((result.command.id, result.outcome)),
+        )
+        .await;
+
+        assert_eq!(
+            results,
+            [(
+                1,
+                ReplayOutcome::Failed {
+                    message: "Lost the connection to Home Assistant during the pipeline run"
+                        .to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_queues() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("queue.json");
+        std::fs::write(&path, "[{").unwrap();
+
+        let queue = CommandQueue::load(path.clone());
+        assert!(queue.commands().is_empty());
+        queue.push("Hello", "default", None, false).unwrap();
+        assert_eq!(CommandQueue::load(path).commands().len(), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Manager;
+
+use super::{Connection, HomeAssistantClient, WebSocketError};
+use crate::settings::{HomeAssistantSettings, NetworkSettings};
+
+// Retries start after about a second and double up to a few minutes.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// A connection that stayed up this long starts the backoff over when it drops.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+// The state of the app's connection to Home Assistant, sent to the webview
+// as `connection-status` events.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    // No server or access token set up.
+    #[default]
+    NotConfigured,
+    Connecting {
+        attempt: u32,
+    },
+    Connected {
+        ha_version: String,
+    },
+    // The access token was rejected. Not retried until settings change, as
+    // Home Assistant bans IPs after too many failed logins.
+    AuthFailed {
+        message: String,
+    },
+    // Waiting before trying again, after connecting failed or the connection
+    // was lost.
+    Backoff {
+        reason: BackoffReason,
+        retry_in_seconds: u64,
+        message: String,
+    },
+    // The profile is set to use the REST API only, so there's no connection
+    // to keep up.
+    RestOnly,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackoffReason {
+    // Connecting failed, e.g. because the server is down.
+    Unreachable,
+    // A connection that was up dropped.
+    ConnectionLost,
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionStatus::NotConfigured => write!(f, "Home Assistant not set up"),
+            ConnectionStatus::Connecting { .. } => write!(f, "Connecting to Home Assistant..."),
+            ConnectionStatus::Connected { ha_version } => {
+                write!(f, "Connected to Home Assistant {}", ha_version)
+            }
+            ConnectionStatus::AuthFailed { .. } => {
+                write!(f, "Home Assistant rejected the access token")
+            }
+            ConnectionStatus::Backoff {
+                reason,
+                retry_in_seconds,
+                ..
+            } => {
+                let problem = match reason {
+                    BackoffReason::Unreachable => "Could not reach Home Assistant",
+                    BackoffReason::ConnectionLost => "Lost the connection to Home Assistant",
+                };
+                write!(f, "{}, retrying in {}s", problem, retry_in_seconds)
+            }
+            ConnectionStatus::RestOnly => write!(f, "Using the Home Assistant REST API"),
+        }
+    }
+}
+
+// Keep a connection to one server open, reconnecting with backoff when it
+// can't be reached or drops. Runs until settings change, when it is aborted,
+// or until the access token is rejected.
+pub(super) async fn supervise(
+    app_handle: tauri::AppHandle,
+    home_assistant: HomeAssistantSettings,
+    network: NetworkSettings,
+    target: Option<Value>,
+) {
+    let report = |status: ConnectionStatus, connection: Option<Arc<Connection>>| {
+        app_handle
+            .state::<HomeAssistantClient>()
+            .report(&app_handle, &target, status, connection)
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        if !report(ConnectionStatus::Connecting { attempt }, None) {
+            return;
+        }
+        log::info!(
+            "Connecting to Home Assistant at {} (attempt {})...",
+            home_assistant.base_url,
+            attempt
+        );
+
+        let (reason, message) = match Connection::open(&home_assistant, &network).await {
+            Ok(connection) => {
+                let connection = Arc::new(connection);
+                log::info!("Connected to Home Assistant {}", connection.ha_version());
+                let status = ConnectionStatus::Connected {
+                    ha_version: connection.ha_version().to_string(),
+                };
+                if !report(status, Some(connection.clone())) {
+                    return;
+                }
+                tauri::async_runtime::spawn(super::queue::replay(app_handle.clone()));
+
+                let connected_at = Instant::now();
+                let reason = connection.closed().await;
+                log::warn!("Lost the connection to Home Assistant: {}", reason);
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    attempt = 0;
+                }
+                (BackoffReason::ConnectionLost, reason)
+            }
+            Err(WebSocketError::Auth(message)) => {
+                log::error!(
+                    "Home Assistant rejected the access token, not retrying until settings change: {}",
+                    message
+                );
+                report(ConnectionStatus::AuthFailed { message }, None);
+                return;
+            }
+            Err(e) => {
+                log::warn!("Could not connect to Home Assistant: {}", e);
+                (BackoffReason::Unreachable, e.to_string())
+            }
+        };
+
+        let delay = backoff(attempt);
+        let status = ConnectionStatus::Backoff {
+            reason,
+            retry_in_seconds: (delay.as_secs_f64().round() as u64).max(1),
+            message,
+        };
+        if !report(status, None) {
+            return;
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// How long to wait after `attempt` attempts in a row failed. Half of it is
+// random, so that when a server restarts the clients of it don't all come
+// back at the same moment.
+fn backoff(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_why_it_backs_off() {
+        let status = ConnectionStatus::Backoff {
+            reason: BackoffReason::ConnectionLost,
+            retry_in_seconds: 4,
+            message: "Connection reset".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({
+                "state": "backoff",
+                "reason": "connection_lost",
+                "retry_in_seconds": 4,
+                "message": "Connection reset",
+            })
+        );
+        assert_eq!(
+            status.to_string(),
+            "Lost the connection to Home Assistant, retrying in 4s"
+        );
+    }
+
+    #[test]
+    fn doubles_the_backoff() {
+        for (attempt, full) in [(0, 1), (1, 1), (2, 2), (3, 4), (8, 128)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..100 {
+                let delay = backoff(attempt);
+                assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn caps_the_backoff() {
+        for attempt in [9, 10, 64, u32::MAX] {
+            let delay = backoff(attempt);
+            assert!(
+                delay >= MAX_BACKOFF / 2 && delay <= MAX_BACKOFF,
+                "{:?}",
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn spreads_out_retries() {
+        let delays: Vec<Duration> = (0..10).map(|_| backoff(5)).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+}
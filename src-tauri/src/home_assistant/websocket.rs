@@ -0,0 +1,972 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+
+use crate::http::{self, Scheme};
+use crate::settings::{HomeAssistantSettings, NetworkSettings};
+
+// How long connecting and authenticating may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Home Assistant doesn't notice a dead connection on its own, so send a ping
+// this often and give up on the connection if the pong doesn't come back.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest proxy response header accepted when tunnelling through a proxy.
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    // The host name could not be resolved.
+    Dns(String),
+    // Nothing accepted the TCP connection.
+    Tcp(String),
+    // The server's certificate could not be verified.
+    Tls(String),
+    Timeout(String),
+    // Home Assistant rejected the access token (`auth_invalid`). Trying again
+    // with the same token won't help, and too many attempts get the IP banned.
+    Auth(String),
+    // Something that isn't Home Assistant answered, e.g. a proxy refusing
+    // the upgrade to a websocket.
+    Protocol(String),
+    // The connection was lost, or was never there.
+    Closed(String),
+    // The connection was lost after Home Assistant accepted a message, e.g.
+    // during a pipeline run, so what the message asked for may have happened.
+    Interrupted(String),
+    // Home Assistant answered a message with `success: false`.
+    Failed { code: String, message: String },
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebSocketError::Dns(message)
+            | WebSocketError::Tcp(message)
+            | WebSocketError::Tls(message)
+            | WebSocketError::Timeout(message)
+            | WebSocketError::Auth(message)
+            | WebSocketError::Protocol(message)
+            | WebSocketError::Closed(message)
+            | WebSocketError::Interrupted(message) => write!(f, "{}", message),
+            WebSocketError::Failed { code, message } => write!(f, "{} ({})", message, code),
+        }
+    }
+}
+
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+type Stream = Box<dyn AsyncStream>;
+
+type Reply = Result<Value, WebSocketError>;
+
+// State shared between a connection and the tasks reading from and writing
+// to its socket.
+struct Inner {
+    outgoing: mpsc::UnboundedSender<Message>,
+    next_id: AtomicU64,
+    // Messages waiting for their `result` (or `pong`), by id.
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    // Where to send `event` messages, by the id of the message that
+    // subscribed to them.
+    subscriptions: Mutex<HashMap<u64, mpsc::UnboundedSender<Value>>>,
+    // Why the connection closed, once it has.
+    closed: watch::Sender<Option<String>>,
+}
+
+// An authenticated connection to the Home Assistant websocket API. Messages
+// are matched to their results by id, so any number can be in flight.
+pub struct Connection {
+    inner: Arc<Inner>,
+    ha_version: String,
+}
+
+// Events for one subscription, until it is dropped or the connection closes.
+pub struct Subscription {
+    id: u64,
+    events: mpsc::UnboundedReceiver<Value>,
+    inner: Arc<Inner>,
+}
+
+impl Connection {
+    // Connect to the server in `home_assistant` and authenticate with its
+    // access token. The proxy and CA certificates from `network` apply.
+    pub async fn open(
+        home_assistant: &HomeAssistantSettings,
+        network: &NetworkSettings,
+    ) -> Result<Connection, WebSocketError> {
+        let url = home_assistant
+            .websocket_url()
+            .map_err(WebSocketError::Protocol)?;
+
+        let (socket, ha_version) = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            connect(&url, network, &home_assistant.access_token),
+        )
+        .await
+        .map_err(|_| {
+            WebSocketError::Timeout(format!("{} did not respond in time", url_origin(&url)))
+        })??;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(Inner {
+            outgoing: tx,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+            closed: watch::channel(None).0,
+        });
+
+        let (sink, stream) = socket.split();
+        tokio::spawn(write_messages(sink, rx));
+        tokio::spawn(read_messages(inner.clone(), stream));
+        tokio::spawn(keep_alive(inner.clone()));
+
+        Ok(Connection { inner, ha_version })
+    }
+
+    pub fn ha_version(&self) -> &str {
+        &self.ha_version
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.borrow().is_some()
+    }
+
+    // Wait for the connection to close and return why it did.
+    pub async fn closed(&self) -> String {
+        let mut closed = self.inner.closed.subscribe();
+        let reason = closed.wait_for(Option::is_some).await;
+        reason
+            .map(|reason| reason.clone().unwrap_or_default())
+            .unwrap_or_default()
+    }
+
+    // Send a message, e.g. `{ "type": "get_config" }`, and wait for its result.
+    pub async fn call(&self, message: Value) -> Result<Value, WebSocketError> {
+        self.inner.call(message).await
+    }
+
+    // Send a message that subscribes to events, e.g. `subscribe_events` or
+    // `assist_pipeline/run`, and return the events once it succeeded.
+    pub async fn subscribe(&self, message: Value) -> Result<Subscription, WebSocketError> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+
+        // Listen before sending, as the first events can follow the result
+        // immediately.
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.subscriptions.lock().unwrap().insert(id, tx);
+        let subscription = Subscription {
+            id,
+            events: rx,
+            inner: self.inner.clone(),
+        };
+
+        self.inner.send(id, message).await?;
+        Ok(subscription)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.inner.outgoing.send(Message::Close(None));
+    }
+}
+
+impl Subscription {
+    // The next event, or `None` once the connection has closed.
+    pub async fn next(&mut self) -> Option<Value> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.inner.subscriptions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Inner {
+    async fn call(&self, message: Value) -> Result<Value, WebSocketError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send(id, message).await
+    }
+
+    // Send a message with the given id and wait for the reply to it.
+    async fn send(&self, id: u64, mut message: Value) -> Result<Value, WebSocketError> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        // Checked only once the reply is pending: `close` marks the connection
+        // closed before failing what is pending, so either it fails this
+        // message too or it is seen here. Checked first, a close in between
+        // would leave the reply waiting forever.
+        if let Some(reason) = self.closed.borrow().clone() {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(WebSocketError::Closed(reason));
+        }
+
+        message["id"] = json!(id);
+        if self
+            .outgoing
+            .send(Message::Text(message.to_string()))
+            .is_err()
+        {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(WebSocketError::Closed("Connection closed".to_string()));
+        }
+
+        let reply = rx
+            .await
+            .unwrap_or_else(|_| Err(WebSocketError::Closed("Connection closed".to_string())));
+        if reply.is_err() {
+            self.subscriptions.lock().unwrap().remove(&id);
+        }
+        reply
+    }
+
+    fn handle(&self, message: Value) {
+        let Some(id) = message["id"].as_u64() else {
+            return;
+        };
+
+        match message["type"].as_str() {
+            Some("result") => {
+                let reply = if message["success"].as_bool() == Some(true) {
+                    Ok(message["result"].clone())
+                } else {
+                    Err(WebSocketError::Failed {
+                        code: message["error"]["code"]
+                            .as_str()
+                            .unwrap_or("unknown_error")
+                            .to_string(),
+                        message: message["error"]["message"]
+                            .as_str()
+                            .unwrap_or("Unknown error")
+                            .to_string(),
+                    })
+                };
+                if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+                    let _ = tx.send(reply);
+                }
+            }
+            Some("pong") => {
+                if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+                    let _ = tx.send(Ok(Value::Null));
+                }
+            }
+            Some("event") => {
+                if let Some(tx) = self.subscriptions.lock().unwrap().get(&id) {
+                    let _ = tx.send(message["event"].clone());
+                }
+            }
+            _ => log::debug!("Ignoring websocket message: {}", message),
+        }
+    }
+
+    // Fail everything still waiting and end all subscriptions.
+    fn close(&self, reason: String) {
+        log::info!("Home Assistant connection closed: {}", reason);
+        self.closed.send_replace(Some(reason.clone()));
+        for (_, tx) in self.pending.lock().unwrap().drain() {
+            let _ = tx.send(Err(WebSocketError::Closed(reason.clone())));
+        }
+        self.subscriptions.lock().unwrap().clear();
+    }
+}
+
+async fn write_messages(
+    mut sink: futures_util::stream::SplitSink<WebSocketStream<Stream>, Message>,
+    mut rx: mpsc::UnboundedReceiver<Message>,
+) {
+    while let Some(message) = rx.recv().await {
+        let close = matches!(message, Message::Close(_));
+        if sink.send(message).await.is_err() || close {
+            break;
+        }
+    }
+}
+
+async fn read_messages(
+    inner: Arc<Inner>,
+    mut stream: futures_util::stream::SplitStream<WebSocketStream<Stream>>,
+) {
+    let reason = loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str::<Value>(&text) {
+                // Home Assistant may send several messages as one array.
+                Ok(Value::Array(messages)) => messages.into_iter().for_each(|m| inner.handle(m)),
+                Ok(message) => inner.handle(message),
+                Err(e) => log::warn!("Invalid websocket message: {}", e),
+            },
+            Some(Ok(Message::Close(frame))) => {
+                break frame.map_or("Closed by Home Assistant".to_string(), |frame| {
+                    format!("Closed by Home Assistant: {}", frame.reason)
+                })
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => break e.to_string(),
+            None => break "Connection closed".to_string(),
+        }
+    };
+    inner.close(reason);
+}
+
+async fn keep_alive(inner: Arc<Inner>) {
+    let mut closed = inner.closed.subscribe();
+    loop {
+        tokio::select! {
+            _ = closed.wait_for(Option::is_some) => return,
+            _ = tokio::time::sleep(PING_INTERVAL) => {}
+        }
+
+        let ping = tokio::time::timeout(PONG_TIMEOUT, inner.call(json!({ "type": "ping" })));
+        if !matches!(ping.await, Ok(Ok(_))) {
+            log::warn!("Home Assistant did not answer a ping, closing the connection");
+            let _ = inner.outgoing.send(Message::Close(None));
+            inner.close("Home Assistant stopped responding".to_string());
+            return;
+        }
+    }
+}
+
+// Open the socket and authenticate. Returns the Home Assistant version.
+async fn connect(
+    url: &Url,
+    network: &NetworkSettings,
+    access_token: &str,
+) -> Result<(WebSocketStream<Stream>, String), WebSocketError> {
+    let stream = open_stream(url, network).await?;
+    let (mut socket, _) = tokio_tungstenite::client_async(url.as_str(), stream)
+        .await
+        .map_err(|e| {
+            WebSocketError::Protocol(format!(
+                "Could not open a websocket to {}: {}",
+                url_origin(url),
+                e
+            ))
+        })?;
+
+    let message = next_message(&mut socket).await?;
+    if message["type"] != "auth_required" {
+        return Err(unexpected(&message));
+    }
+    let ha_version = message["ha_version"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+
+    let auth = json!({ "type": "auth", "access_token": access_token });
+    socket
+        .send(Message::Text(auth.to_string()))
+        .await
+        .map_err(|e| WebSocketError::Closed(e.to_string()))?;
+
+    let message = next_message(&mut socket).await?;
+    match message["type"].as_str() {
+        Some("auth_ok") => Ok((socket, ha_version)),
+        Some("auth_invalid") => Err(WebSocketError::Auth(format!(
+            "Home Assistant rejected the access token: {}",
+            message["message"]
+                .as_str()
+                .unwrap_or("Invalid access token")
+        ))),
+        _ => Err(unexpected(&message)),
+    }
+}
+
+async fn next_message(socket: &mut WebSocketStream<Stream>) -> Result<Value, WebSocketError> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                return serde_json::from_str(&text).map_err(|e| {
+                    WebSocketError::Protocol(format!("Invalid message from Home Assistant: {}", e))
+                })
+            }
+            Some(Ok(Message::Close(_))) | None => {
+                return Err(WebSocketError::Closed(
+                    "Home Assistant closed the connection".to_string(),
+                ))
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(WebSocketError::Closed(e.to_string())),
+        }
+    }
+}
+
+fn unexpected(message: &Value) -> WebSocketError {
+    WebSocketError::Protocol(format!(
+        "Unexpected message from Home Assistant: {}",
+        message
+    ))
+}
+
+// The server's address for messages, without the path or any credentials.
+fn url_origin(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+// Host names as used for DNS and TLS, i.e. IPv6 addresses without brackets.
+fn host(url: &Url) -> Result<String, WebSocketError> {
+    url.host_str()
+        .map(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        })
+        .ok_or_else(|| WebSocketError::Protocol(format!("{} has no host", url)))
+}
+
+async fn open_stream(url: &Url, network: &NetworkSettings) -> Result<Stream, WebSocketError> {
+    let host = host(url)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let environment = |name: &str| std::env::var(name).ok();
+    let stream = match proxy_for(url, &host, network, &environment)? {
+        Some(proxy) => tunnel(&proxy, url, network).await?,
+        None => Box::new(tcp_connect(&host, port).await?) as Stream,
+    };
+
+    if url.scheme() == "wss" {
+        tls(stream, &host, network).await
+    } else {
+        Ok(stream)
+    }
+}
+
+// The proxy to reach `url` through, if any: the one from settings, or else
+// the one for its scheme from the environment, as for `http::client`. Hosts
+// in `network.no_proxy` are reached directly, as are those in NO_PROXY when
+// the proxy comes from the environment.
+fn proxy_for(
+    url: &Url,
+    host: &str,
+    network: &NetworkSettings,
+    environment: &impl Fn(&str) -> Option<String>,
+) -> Result<Option<Url>, WebSocketError> {
+    if let Some(proxy) = &network.proxy {
+        let bypass = bypasses_proxy(host, &network.no_proxy);
+        return Ok(Some(proxy.clone()).filter(|_| !bypass));
+    }
+
+    let scheme = if url.scheme() == "wss" {
+        Scheme::Https
+    } else {
+        Scheme::Http
+    };
+    let proxies = http::environment_proxies(environment);
+    let Some((_, proxy)) = proxies
+        .iter()
+        .find(|(proxy_scheme, _)| *proxy_scheme == scheme)
+        .or_else(|| {
+            proxies
+                .iter()
+                .find(|(proxy_scheme, _)| *proxy_scheme == Scheme::All)
+        })
+    else {
+        return Ok(None);
+    };
+    let no_proxy: Vec<String> = http::no_proxy_list(&network.no_proxy, environment)
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    if bypasses_proxy(host, &no_proxy) {
+        return Ok(None);
+    }
+
+    // Like reqwest, take a proxy without a scheme to be an HTTP proxy.
+    let proxy = if proxy.contains("://") {
+        proxy.clone()
+    } else {
+        format!("http://{}", proxy)
+    };
+    Url::parse(&proxy)
+        .map(Some)
+        .map_err(|e| WebSocketError::Protocol(format!("Invalid proxy {}: {}", proxy, e)))
+}
+
+// Hosts in `no_proxy` match themselves and their subdomains, `*` matches all.
+fn bypasses_proxy(host: &str, no_proxy: &[String]) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().trim_start_matches('.');
+        entry == "*"
+            || host.eq_ignore_ascii_case(entry)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+    })
+}
+
+async fn tcp_connect(host: &str, port: u16) -> Result<TcpStream, WebSocketError> {
+    let addresses: Vec<_> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| WebSocketError::Dns(format!("Could not resolve {}: {}", host, e)))?
+        .collect();
+
+    TcpStream::connect(addresses.as_slice())
+        .await
+        .map_err(|e| WebSocketError::Tcp(format!("Could not connect to {}:{}: {}", host, port, e)))
+}
+
+// Reach `url` through an HTTP CONNECT tunnel.
+async fn tunnel(
+    proxy: &Url,
+    url: &Url,
+    network: &NetworkSettings,
+) -> Result<Stream, WebSocketError> {
+    let proxy_host = host(proxy)?;
+    let proxy_port = proxy.port_or_known_default().unwrap_or(80);
+    let stream = tcp_connect(&proxy_host, proxy_port)
+        .await
+        .map_err(|e| match e {
+            WebSocketError::Dns(message) | WebSocketError::Tcp(message) => {
+                WebSocketError::Tcp(format!("Could not reach the proxy: {}", message))
+            }
+            e => e,
+        })?;
+    let mut stream: Stream = Box::new(stream);
+    if proxy.scheme() == "https" {
+        stream = tls(stream, &proxy_host, network).await?;
+    }
+
+    let target = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or(80)
+    );
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if !proxy.username().is_empty() {
+        let credentials = format!("{}:{}", proxy.username(), proxy.password().unwrap_or(""));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+
+    let proxy_error = |e: std::io::Error| {
+        WebSocketError::Tcp(format!(
+            "Could not reach the proxy {}: {}",
+            url_origin(proxy),
+            e
+        ))
+    };
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(proxy_error)?;
+
+    // Read the response a byte at a time, so nothing after it is consumed.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_PROXY_RESPONSE {
+            return Err(WebSocketError::Protocol(format!(
+                "Invalid response from the proxy {}",
+                url_origin(proxy)
+            )));
+        }
+        response.push(stream.read_u8().await.map_err(proxy_error)?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(WebSocketError::Protocol(format!(
+            "The proxy {} refused to connect to {}: {}",
+            url_origin(proxy),
+            target,
+            status
+        )));
+    }
+
+    Ok(stream)
+}
+
+async fn tls(
+    stream: Stream,
+    host: &str,
+    network: &NetworkSettings,
+) -> Result<Stream, WebSocketError> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| WebSocketError::Tls(format!("Invalid server name {}: {}", host, e)))?;
+
+    let stream = TlsConnector::from(tls_config(network)?)
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| {
+            WebSocketError::Tls(format!(
+                "Could not verify the TLS certificate of {}: {}. If it uses a private CA, \
+                 add the CA certificate in the network settings.",
+                host, e
+            ))
+        })?;
+
+    Ok(Box::new(stream))
+}
+
+// The usual web PKI roots, plus the CA certificates from settings.
+fn tls_config(network: &NetworkSettings) -> Result<Arc<ClientConfig>, WebSocketError> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    for path in &network.ca_certificates {
+        let pem = std::fs::read(path).map_err(|e| {
+            WebSocketError::Tls(format!(
+                "Could not read CA certificates from {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        for certificate in rustls_pemfile::certs(&mut pem.as_slice()) {
+            let added = certificate
+                .map_err(|e| e.to_string())
+                .and_then(|certificate| roots.add(certificate).map_err(|e| e.to_string()));
+            if let Err(e) = added {
+                return Err(WebSocketError::Tls(format!(
+                    "Invalid CA certificates in {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+    }
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| WebSocketError::Tls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use tokio::net::TcpListener;
+
+    const TOKEN: &str = "token";
+
+    type ServerSocket = WebSocketStream<TcpStream>;
+
+    // A stand-in for Home Assistant that authenticates one client and hands
+    // the socket to `session`.
+    async fn serve<F, Fut>(session: F) -> HomeAssistantSettings
+    where
+        F: FnOnce(ServerSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            send(
+                &mut socket,
+                json!({ "type": "auth_required", "ha_version": "2024.6.0" }),
+            )
+            .await;
+            let auth = receive(&mut socket).await.unwrap();
+            assert_eq!(auth["type"], "auth");
+            if auth["access_token"] == TOKEN {
+                send(&mut socket, json!({ "type": "auth_ok" })).await;
+                session(socket).await;
+            } else {
+                let message = "Invalid access token or password";
+                send(
+                    &mut socket,
+                    json!({ "type": "auth_invalid", "message": message }),
+                )
+                .await;
+            }
+        });
+
+        HomeAssistantSettings {
+            access_token: TOKEN.to_string(),
+            base_url: base_url.parse().unwrap(),
+            ..HomeAssistantSettings::default()
+        }
+    }
+
+    async fn send(socket: &mut ServerSocket, message: Value) {
+        socket
+            .send(Message::Text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // The next message from the client, or `None` once it closed.
+    async fn receive(socket: &mut ServerSocket) -> Option<Value> {
+        loop {
+            match socket.next().await? {
+                Ok(Message::Text(text)) => return Some(serde_json::from_str(&text).unwrap()),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    async fn open(home_assistant: &HomeAssistantSettings) -> Result<Connection, WebSocketError> {
+        Connection::open(home_assistant, &NetworkSettings::default()).await
+    }
+
+    #[tokio::test]
+    async fn authenticates() {
+        let home_assistant = serve(|_| async {}).await;
+        let connection = open(&home_assistant).await.unwrap();
+        assert_eq!(connection.ha_version(), "2024.6.0");
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_tokens() {
+        let mut home_assistant = serve(|_| async {}).await;
+        home_assistant.access_token = "wrong".to_string();
+        match open(&home_assistant).await {
+            Err(WebSocketError::Auth(message)) => {
+                assert!(message.contains("Invalid access token or password"))
+            }
+            other => panic!("unexpected {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_servers_that_are_not_home_assistant() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            send(&mut socket, json!({ "type": "hello" })).await;
+        });
+        let home_assistant = HomeAssistantSettings {
+            base_url: base_url.parse().unwrap(),
+            ..HomeAssistantSettings::default()
+        };
+
+        assert!(matches!(
+            open(&home_assistant).await,
+            Err(WebSocketError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn matches_results_to_messages() {
+        let home_assistant = serve(|mut socket| async move {
+            let first = receive(&mut socket).await.unwrap();
+            let second = receive(&mut socket).await.unwrap();
+            let third = receive(&mut socket).await.unwrap();
+            assert_ne!(first["id"], second["id"]);
+            // Answer out of order, partly batched into one message.
+            let result = |message: &Value| {
+                json!({
+                    "id": message["id"],
+                    "type": "result",
+                    "success": true,
+                    "result": message["type"],
+                })
+            };
+            send(&mut socket, json!([result(&second), result(&first)])).await;
+            let error = json!({ "code": "unknown_command", "message": "Unknown command." });
+            send(
+                &mut socket,
+                json!({ "id": third["id"], "type": "result", "success": false, "error": error }),
+            )
+            .await;
+            receive(&mut socket).await;
+        })
+        .await;
+        let connection = open(&home_assistant).await.unwrap();
+
+        let (first, second, third) = tokio::join!(
+            connection.call(json!({ "type": "get_config" })),
+            connection.call(json!({ "type": "get_states" })),
+            connection.call(json!({ "type": "no_such_command" })),
+        );
+        assert_eq!(first.unwrap(), "get_config");
+        assert_eq!(second.unwrap(), "get_states");
+        match third {
+            Err(WebSocketError::Failed { code, message }) => {
+                assert_eq!(code, "unknown_command");
+                assert_eq!(message, "Unknown command.");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_events_to_their_subscription() {
+        let home_assistant = serve(|mut socket| async move {
+            let subscribe = receive(&mut socket).await.unwrap();
+            let id = subscribe["id"].as_u64().unwrap();
+            let event = |id: u64, n: u64| json!({ "id": id, "type": "event", "event": { "n": n } });
+            send(
+                &mut socket,
+                json!({ "id": id, "type": "result", "success": true, "result": null }),
+            )
+            .await;
+            send(&mut socket, event(id, 1)).await;
+            // For a subscription the client doesn't have.
+            send(&mut socket, event(id + 100, 2)).await;
+            send(&mut socket, event(id, 3)).await;
+            socket.close(None).await.unwrap();
+        })
+        .await;
+        let connection = open(&home_assistant).await.unwrap();
+
+        let mut subscription = connection
+            .subscribe(json!({ "type": "subscribe_events" }))
+            .await
+            .unwrap();
+        assert_eq!(subscription.next().await, Some(json!({ "n": 1 })));
+        assert_eq!(subscription.next().await, Some(json!({ "n": 3 })));
+        // Subscriptions end when the connection closes.
+        assert_eq!(subscription.next().await, None);
+        assert!(connection.is_closed());
+        assert!(matches!(
+            connection.call(json!({ "type": "ping" })).await,
+            Err(WebSocketError::Closed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_when_pings_go_unanswered() {
+        let (pinged, ping) = oneshot::channel();
+        let home_assistant = serve(|mut socket| async move {
+            let message = receive(&mut socket).await.unwrap();
+            let _ = pinged.send(message["type"].clone());
+            // Hang without answering or reading anything more.
+            std::future::pending::<()>().await;
+            drop(socket);
+        })
+        .await;
+        let connection = open(&home_assistant).await.unwrap();
+
+        // Skip ahead through the ping interval and pong timeout.
+        tokio::time::pause();
+        assert_eq!(
+            connection.closed().await,
+            "Home Assistant stopped responding"
+        );
+        assert_eq!(ping.await.unwrap(), "ping");
+    }
+
+    #[tokio::test]
+    async fn fails_messages_sent_after_closing() {
+        // The writer still holds the receiving end, as it does until the
+        // socket is gone, so sending the message itself succeeds.
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let inner = Inner {
+            outgoing: tx,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+            closed: watch::channel(None).0,
+        };
+        inner.close("Closed by Home Assistant".to_string());
+
+        let reply = tokio::time::timeout(
+            Duration::from_secs(5),
+            inner.call(json!({ "type": "ping" })),
+        )
+        .await
+        .expect("the call should fail rather than wait");
+        assert!(matches!(reply, Err(WebSocketError::Closed(_))));
+        assert!(inner.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn uses_proxies_from_the_environment() {
+        let variables = [
+            ("HTTPS_PROXY", "http://secure.example:3128"),
+            ("http_proxy", "plain.example:8080"),
+            ("NO_PROXY", "localhost"),
+        ];
+        let environment = |name: &str| {
+            variables
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let network = NetworkSettings {
+            no_proxy: vec![".lan".to_string()],
+            ..NetworkSettings::default()
+        };
+        let proxy = |url: &str| {
+            let url: Url = url.parse().unwrap();
+            proxy_for(&url, &host(&url).unwrap(), &network, &environment)
+                .unwrap()
+                .map(|proxy| proxy.to_string())
+        };
+
+        assert_eq!(
+            proxy("wss://ha.example/api/websocket").as_deref(),
+            Some("http://secure.example:3128/")
+        );
+        assert_eq!(
+            proxy("ws://ha.example/api/websocket").as_deref(),
+            Some("http://plain.example:8080/")
+        );
+        // NO_PROXY from the environment and no_proxy from settings both apply.
+        assert_eq!(proxy("ws://localhost:8123/api/websocket"), None);
+        assert_eq!(proxy("wss://ha.lan/api/websocket"), None);
+
+        // A proxy in settings replaces the environment's.
+        let network = NetworkSettings {
+            proxy: Some("http://settings.example:3128".parse().unwrap()),
+            ..NetworkSettings::default()
+        };
+        let url: Url = "ws://localhost:8123/api/websocket".parse().unwrap();
+        assert_eq!(
+            proxy_for(&url, "localhost", &network, &environment)
+                .unwrap()
+                .map(|proxy| proxy.to_string())
+                .as_deref(),
+            Some("http://settings.example:3128/")
+        );
+        assert_eq!(
+            proxy_for(
+                &url,
+                "localhost",
+                &NetworkSettings::default(),
+                &|_: &str| None
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn bypasses_proxy_for_listed_hosts() {
+        let no_proxy = ["localhost".to_string(), ".example.com".to_string()];
+        assert!(bypasses_proxy("localhost", &no_proxy));
+        assert!(bypasses_proxy("LocalHost", &no_proxy));
+        assert!(bypasses_proxy("example.com", &no_proxy));
+        assert!(bypasses_proxy("ha.example.com", &no_proxy));
+        assert!(!bypasses_proxy("example.org", &no_proxy));
+        assert!(!bypasses_proxy("notexample.com", &no_proxy));
+        assert!(!bypasses_proxy("localhost.example.org", &no_proxy));
+        assert!(!bypasses_proxy("localhost", &[]));
+        assert!(bypasses_proxy("192.168.1.2", &[" * ".to_string()]));
+    }
+}
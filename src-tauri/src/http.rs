@@ -0,0 +1,371 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::settings::NetworkSettings;
+
+#[derive(Debug)]
+pub enum HttpError {
+    // The server's certificate could not be verified, e.g. because it was
+    // issued by a private CA that isn't in `network.ca_certificates`.
+    Tls(String),
+    // The server rejected the credentials that were sent.
+    Auth(String),
+    // The server could not be reached, e.g. because it is restarting. The
+    // request was never sent, so trying later is safe.
+    Unreachable(String),
+    // The request was sent but the server did not respond in time. It may
+    // still have been handled, so it must not be sent again on its own.
+    Timeout(String),
+    Other(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Tls(message)
+            | HttpError::Auth(message)
+            | HttpError::Unreachable(message)
+            | HttpError::Timeout(message)
+            | HttpError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<String> for HttpError {
+    fn from(message: String) -> Self {
+        HttpError::Other(message)
+    }
+}
+
+// A client for requests made by the app itself, using the proxy and CA
+// certificates from the network settings. Without a proxy in settings, the
+// usual HTTPS_PROXY, HTTP_PROXY and ALL_PROXY environment variables apply.
+// Hosts in `network.no_proxy` bypass either kind of proxy.
+pub fn client(network: &NetworkSettings, timeout: Duration) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder().timeout(timeout);
+
+    if let Some(proxy) = &network.proxy {
+        let no_proxy = reqwest::NoProxy::from_string(&network.no_proxy.join(","));
+        let proxy = reqwest::Proxy::all(proxy.as_str())
+            .map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    } else if !network.no_proxy.is_empty() {
+        // Adding any proxy stops reqwest from reading the environment, so the
+        // proxies from there are added here along with `network.no_proxy`.
+        let environment = |name: &str| std::env::var(name).ok();
+        let no_proxy = no_proxy_list(&network.no_proxy, &environment);
+        for (scheme, url) in environment_proxies(&environment) {
+            let proxy = match scheme {
+                Scheme::Http => reqwest::Proxy::http(&url),
+                Scheme::Https => reqwest::Proxy::https(&url),
+                Scheme::All => reqwest::Proxy::all(&url),
+            }
+            .map_err(|e| format!("Invalid proxy {}: {}", url, e))?
+            .no_proxy(reqwest::NoProxy::from_string(&no_proxy));
+            builder = builder.proxy(proxy);
+        }
+    }
+
+    for path in &network.ca_certificates {
+        let pem = std::fs::read(path).map_err(|e| {
+            format!(
+                "Could not read CA certificates from {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| format!("Invalid CA certificates in {}: {}", path.display(), e))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Scheme {
+    Http,
+    Https,
+    All,
+}
+
+// Proxies from the environment the way reqwest reads them, upper case names
+// taking precedence. Requests use the first proxy matching their scheme.
+pub(crate) fn environment_proxies(
+    environment: &impl Fn(&str) -> Option<String>,
+) -> Vec<(Scheme, String)> {
+    [
+        (Scheme::Http, "HTTP_PROXY"),
+        (Scheme::Https, "HTTPS_PROXY"),
+        (Scheme::All, "ALL_PROXY"),
+    ]
+    .into_iter()
+    .filter_map(|(scheme, name)| {
+        let url = environment(name)
+            .or_else(|| environment(&name.to_lowercase()))
+            .filter(|url| !url.is_empty())?;
+        Some((scheme, url))
+    })
+    .collect()
+}
+
+// NO_PROXY from the environment followed by the hosts from settings.
+pub(crate) fn no_proxy_list(
+    no_proxy: &[String],
+    environment: &impl Fn(&str) -> Option<String>,
+) -> String {
+    environment("NO_PROXY")
+        .or_else(|| environment("no_proxy"))
+        .filter(|hosts| !hosts.is_empty())
+        .into_iter()
+        .chain(no_proxy.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Send a request to `server`, a description used in error messages, and fail
+// on any status other than success.
+pub async fn send(
+    request: reqwest::RequestBuilder,
+    server: &str,
+) -> Result<reqwest::Response, HttpError> {
+    let response = request.send().await.map_err(|e| {
+        if e.is_timeout() {
+            HttpError::Timeout(format!("{} did not respond in time", server))
+        } else if is_certificate_error(&e) {
+            HttpError::Tls(format!(
+                "Could not verify the TLS certificate of the {}: {}. If it uses a \
+                 private CA, add the CA certificate in the network settings.",
+                server,
+                root_cause(&e)
+            ))
+        } else if e.is_connect() || is_dns_error(&e) {
+            HttpError::Unreachable(format!(
+                "Could not reach the {}: {}",
+                server,
+                root_cause(&e)
+            ))
+        } else {
+            HttpError::Other(format!(
+                "Request to the {} failed: {}",
+                server,
+                root_cause(&e)
+            ))
+        }
+    })?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(HttpError::Auth(format!(
+            "The {} rejected the credentials ({}), check the access token or API key",
+            server, status
+        )));
+    }
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(HttpError::Other(format!(
+            "The {} returned {}: {}",
+            server,
+            status,
+            body.trim()
+        )));
+    }
+
+    Ok(response)
+}
+
+// reqwest reports TLS and DNS failures as generic connection errors, with
+// the actual error further down the chain.
+pub fn is_certificate_error(error: &reqwest::Error) -> bool {
+    chain_contains(error, "certificate")
+}
+
+pub fn is_dns_error(error: &reqwest::Error) -> bool {
+    chain_contains(error, "dns error")
+}
+
+fn chain_contains(error: &reqwest::Error, text: &str) -> bool {
+    let mut source: Option<&dyn std::error::Error> = Some(error);
+    while let Some(error) = source {
+        if error.to_string().to_lowercase().contains(text) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+pub fn root_cause(error: &reqwest::Error) -> String {
+    let mut error: &dyn std::error::Error = error;
+    while let Some(source) = error.source() {
+        error = source;
+    }
+    error.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{CommandError, ErrorKind};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::ServerConfig;
+
+    fn environment(variables: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let variables: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| variables.get(name).cloned()
+    }
+
+    #[test]
+    fn reads_proxies_from_the_environment() {
+        let environment = environment(&[
+            ("HTTPS_PROXY", "http://upper.example:3128"),
+            ("https_proxy", "http://lower.example:3128"),
+            ("http_proxy", "http://lower.example:8080"),
+            ("ALL_PROXY", ""),
+        ]);
+        assert_eq!(
+            environment_proxies(&environment),
+            [
+                (Scheme::Http, "http://lower.example:8080".to_string()),
+                (Scheme::Https, "http://upper.example:3128".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_hosts_from_settings_to_no_proxy() {
+        let no_proxy = ["ha.local".to_string()];
+        assert_eq!(
+            no_proxy_list(&no_proxy, &environment(&[("no_proxy", "localhost,.corp")])),
+            "localhost,.corp,ha.local"
+        );
+        assert_eq!(no_proxy_list(&no_proxy, &environment(&[])), "ha.local");
+    }
+
+    #[test]
+    fn fails_on_invalid_ca_certificates() {
+        let directory = tempfile::tempdir().unwrap();
+        let missing = directory.path().join("missing.pem");
+        let network = NetworkSettings {
+            ca_certificates: vec![missing],
+            ..Default::default()
+        };
+        let error = client(&network, Duration::from_secs(1)).unwrap_err();
+        assert!(
+            error.starts_with("Could not read CA certificates from"),
+            "{}",
+            error
+        );
+
+        let invalid = directory.path().join("invalid.pem");
+        std::fs::write(
+            &invalid,
+            "-----BEGIN CERTIFICATE-----\n!!!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let network = NetworkSettings {
+            ca_certificates: vec![invalid],
+            ..Default::default()
+        };
+        let error = client(&network, Duration::from_secs(1)).unwrap_err();
+        assert!(error.starts_with("Invalid CA certificates in"), "{}", error);
+    }
+
+    // A server with a self-signed certificate, which no client trusts. Returns
+    // its port.
+    async fn serve_untrusted_certificate() -> u16 {
+        let certificates =
+            rustls_pemfile::certs(&mut &include_bytes!("../tests/fixtures/tls/localhost.pem")[..])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+        let key = rustls_pemfile::private_key(
+            &mut &include_bytes!("../tests/fixtures/tls/localhost-key.pem")[..],
+        )
+        .unwrap()
+        .unwrap();
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The client gives up during the handshake.
+            let _ = acceptor.accept(stream).await;
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn reports_refused_connections_as_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let client = client(&NetworkSettings::default(), Duration::from_secs(5)).unwrap();
+
+        let error = send(
+            client.get(format!("http://127.0.0.1:{}/", port)),
+            "test server",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, HttpError::Unreachable(_)), "{}", error);
+        assert_eq!(CommandError::from(error).code, ErrorKind::Network);
+    }
+
+    #[tokio::test]
+    async fn reports_unanswered_requests_as_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // Accept the request but never answer it.
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(stream);
+        });
+        let client = client(&NetworkSettings::default(), Duration::from_millis(500)).unwrap();
+
+        let error = send(
+            client.post(format!("http://127.0.0.1:{}/", port)),
+            "test server",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, HttpError::Timeout(_)), "{}", error);
+        assert_eq!(CommandError::from(error).code, ErrorKind::Network);
+    }
+
+    #[tokio::test]
+    async fn reports_untrusted_certificates_as_tls_errors() {
+        let port = serve_untrusted_certificate().await;
+        let client = client(&NetworkSettings::default(), Duration::from_secs(5)).unwrap();
+
+        let error = send(
+            client.get(format!("https://127.0.0.1:{}/", port)),
+            "test server",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, HttpError::Tls(_)), "{}", error);
+
+        let error = CommandError::from(error);
+        assert_eq!(error.code, ErrorKind::Tls);
+        assert!(error
+            .message
+            .starts_with("Could not verify the TLS certificate of the test server"));
+    }
+}
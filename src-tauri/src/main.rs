@@ -1,237 +1,801 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use actions::Action;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use error::{CommandError, ErrorKind};
+use home_assistant::pipelines::{AssistPipeline, AssistPipelineMutableParams, PipelineList};
+use home_assistant::{
+    CommandQueue, ConnectionStatus, ConnectionTest, HomeAssistantClient, PipelineRun,
+    PipelineStage, QueuedCommand, WebSocketError,
+};
+use http::HttpError;
 use opener::open_browser;
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use tauri::GlobalShortcutManager;
+use serde::Serialize;
+use settings::bundle::{Bundle, ImportReport};
+use settings::{
+    ChangeSource, EffectiveSettings, FieldError, Format, HomeAssistantSettings,
+    HomeAssistantUrls, NetworkSettings, Overrides, Settings, SettingsChanged, SettingsStore,
+};
+use shortcuts::{ShortcutRegistration, Shortcuts};
+use std::time::Duration;
 use tauri::Manager;
 use tauri::SystemTray;
-use tauri::{CustomMenuItem, SystemTrayMenu, SystemTrayMenuItem};
 use tauri_plugin_autostart::MacosLauncher;
 use tauri_plugin_log::LogTarget;
 use url::Url;
 
+mod actions;
+mod cli;
+mod error;
+mod home_assistant;
+mod http;
+mod paths;
+mod secrets;
+mod settings;
+mod shortcuts;
+mod stt;
+mod tray;
+mod window_state;
+
+// How long to wait for media such as TTS audio to download.
+const MEDIA_TIMEOUT: Duration = Duration::from_secs(30);
+
 // No longer needed - imports moved into the setup closure
 
-// Define settings
-#[derive(Serialize, Deserialize)]
-struct HomeAssistantSettings {
-    access_token: String,
-    host: String,
-    port: u16,
-    ssl: bool,
+fn show_window_app(window: tauri::Window) -> Result<(), CommandError> {
+    log::info!("Showing window...");
+    let url = window.url().to_string();
+    if url.contains("settings") {
+        return open_app(window);
+    }
+    window_state::restore(&window);
+    window.show()?;
+    window.set_focus()?;
+    window.emit("focus", {})?;
+
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct TraySettings {
-    double_click_action: String,
+// Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+#[tauri::command]
+fn open_app(window: tauri::Window) -> Result<(), CommandError> {
+    println!("Opening app...");
+
+    let mut url: Url = window.url();
+
+    window.show()?;
+    window.set_focus()?;
+
+    url.set_path("/");
+    println!("Navigating to {}", url);
+
+    window.eval(&format!("window.location.href = '{}';", url))?;
+
+    Ok(())
+}
+
+#[tauri::command]
+fn open_settings(window: tauri::Window) -> Result<(), CommandError> {
+    println!("Opening settings...");
+
+    let mut url: Url = window.url();
+
+    window.show()?;
+    window.set_focus()?;
+
+    url.set_path("/settings");
+    println!("Navigating to {}", url);
+
+    window.eval(&format!("window.location.href = '{}';", url))?;
+
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct Settings {
-    autostart: bool,
+// Tell the webview if settings.json had to be recovered on startup. Done on
+// its first request for settings, as it can't listen for events any earlier.
+fn emit_settings_recovered(app_handle: &tauri::AppHandle, store: &SettingsStore) {
+    if let Some(recovery) = store.take_recovery() {
+        log::warn!("Recovered settings: {}", recovery.message);
+        if let Err(e) = app_handle.emit_all("settings-recovered", recovery) {
+            log::warn!("Could not emit settings-recovered event: {}", e);
+        }
+    }
+}
+
+#[tauri::command]
+fn load_settings(
+    app_handle: tauri::AppHandle,
+    store: tauri::State<SettingsStore>,
+) -> Result<Settings, CommandError> {
+    emit_settings_recovered(&app_handle, &store);
+
+    Ok(store.get())
+}
+
+// Validate and write settings, then update everything that depends on them.
+fn save_settings(
+    app_handle: &tauri::AppHandle,
+    mut settings: Settings,
+) -> Result<(), CommandError> {
+    // Never write settings that would fail to load or connect.
+    let errors = settings::validate(&settings);
+    if !errors.is_empty() {
+        log::warn!("Rejected invalid settings: {:?}", errors);
+        return Err(errors.into());
+    }
+
+    // Settings coming from the webview are always written in the current schema.
+    settings.schema_version = settings::CURRENT_SCHEMA_VERSION;
+
+    let store = app_handle.state::<SettingsStore>();
+    // The window state is kept by the app itself and may have changed since the
+    // webview loaded its copy of the settings.
+    settings.window_state = store.read(|current| current.window_state.clone());
+    store.set(settings).map_err(|e| {
+        CommandError::new(ErrorKind::Io, format!("Could not save settings: {}", e))
+    })?;
+
+    settings_changed(app_handle, ChangeSource::App);
+
+    Ok(())
+}
+
+// Apply new settings to the Rust side and let the webview know about them.
+fn settings_changed(app_handle: &tauri::AppHandle, source: ChangeSource) {
+    let settings = app_handle.state::<SettingsStore>().effective().settings;
+
+    shortcuts::register(app_handle, &settings.shortcuts);
+    tray::refresh(app_handle, &settings);
+    home_assistant::connect(app_handle);
+
+    let change = SettingsChanged { source, settings };
+    if let Err(e) = app_handle.emit_all("settings-changed", change) {
+        log::warn!("Could not emit settings-changed event: {}", e);
+    }
+}
+
+// Settings with environment variables and CLI flags applied, and where each
+// value came from.
+#[tauri::command]
+fn get_effective_settings(
+    app_handle: tauri::AppHandle,
+    store: tauri::State<SettingsStore>,
+) -> Result<EffectiveSettings, CommandError> {
+    emit_settings_recovered(&app_handle, &store);
+
+    Ok(store.effective())
+}
+
+// URLs of the active Home Assistant server, derived from its base URL.
+#[tauri::command]
+fn get_home_assistant_urls(
+    store: tauri::State<SettingsStore>,
+) -> Result<HomeAssistantUrls, CommandError> {
+    let settings = store.effective().settings;
+    Ok(HomeAssistantUrls::new(settings.home_assistant()?)?)
+}
+
+// The state of the app's own connection to Home Assistant, which is then
+// kept up to date by `connection-status` events.
+#[tauri::command]
+fn get_connection_status(
+    client: tauri::State<HomeAssistantClient>,
+) -> Result<ConnectionStatus, CommandError> {
+    Ok(client.status())
+}
+
+// Check server settings from the settings page before they are saved, using
+// the saved network settings.
+#[tauri::command]
+async fn test_connection(
+    store: tauri::State<'_, SettingsStore>,
     home_assistant: HomeAssistantSettings,
-    tray: Option<TraySettings>,
+) -> Result<ConnectionTest, CommandError> {
+    let network = store.read(|settings| settings.network.clone());
+    Ok(home_assistant::test_connection(&home_assistant, &network).await)
 }
 
-#[derive(Debug, Serialize)]
-struct CommandError {
-    message: String,
+// What became of text sent with `run_pipeline`.
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum PipelineOutcome {
+    Finished { run: PipelineRun },
+    // Home Assistant couldn't be reached, so the text waits for it to be back.
+    Queued { command: QueuedCommand },
 }
 
-impl From<serde_json::Error> for CommandError {
-    fn from(error: serde_json::Error) -> Self {
-        CommandError {
-            message: error.to_string(),
+// Run an Assist pipeline on text over the app's own connection to Home
+// Assistant. Progress is sent as `pipeline-event` events, and the finished run
+// is returned. Without a websocket, e.g. in REST only mode, the text goes to
+// the conversation agent of the pipeline, given by `language` and `agent_id`.
+// If Home Assistant can't be reached at all, the text is queued until the
+// websocket is back; `time_sensitive` text is dropped if that takes long.
+#[tauri::command]
+async fn run_pipeline(
+    app_handle: tauri::AppHandle,
+    text: String,
+    pipeline: Option<String>,
+    end_stage: PipelineStage,
+    language: Option<String>,
+    agent_id: Option<String>,
+    time_sensitive: Option<bool>,
+) -> Result<PipelineOutcome, CommandError> {
+    let settings = app_handle.state::<SettingsStore>().effective().settings;
+    let rest_only = settings.home_assistant()?.rest_only;
+    let connected = app_handle.state::<HomeAssistantClient>().connection().is_ok();
+    if connected && !rest_only {
+        match home_assistant::run_text(&app_handle, &text, pipeline.as_deref(), end_stage).await {
+            Ok(run) => return Ok(PipelineOutcome::Finished { run }),
+            // The connection can drop between checking for it and the run,
+            // e.g. as Home Assistant restarts. Once the run started, the
+            // error is `Interrupted` instead, since the command may have run.
+            Err(WebSocketError::Closed(message)) => {
+                log::warn!("Lost the websocket, using the REST API: {}", message)
+            }
+            Err(e) => return Err(e.into()),
         }
+    } else {
+        log::info!("Not connected over the websocket, using the REST API");
     }
-}
 
-fn show_window_app(window: tauri::Window) {
-    log::info!("Showing window...");
-    let url = window.url().to_string();
-    if url.contains("settings") {
-        open_app(window);
-        return;
+    let result = home_assistant::run_text_rest(
+        &app_handle,
+        &text,
+        pipeline.as_deref(),
+        language.as_deref(),
+        agent_id.as_deref(),
+    )
+    .await;
+    match result {
+        Ok(run) => Ok(PipelineOutcome::Finished { run }),
+        // Queued commands are replayed over the websocket, which REST only
+        // profiles don't have.
+        // Only commands that never reached Home Assistant are queued. Errors
+        // it answered with, and timeouts, after which the command may still
+        // have run, are reported right away.
+        Err(HttpError::Unreachable(message)) if !rest_only => {
+            log::warn!("Could not reach Home Assistant: {}", message);
+            let command = app_handle.state::<CommandQueue>().push(
+                &text,
+                &settings.active_profile,
+                pipeline.as_deref(),
+                time_sensitive.unwrap_or(false),
+            );
+            let command = command.map_err(|e| CommandError::new(ErrorKind::Io, e))?;
+            Ok(PipelineOutcome::Queued { command })
+        }
+        Err(e) => Err(e.into()),
     }
-    window.show().expect("failed to show the window");
-    window.set_focus().expect("failed to focus the window");
-    window
-        .emit("focus", {})
-        .expect("failed to emit focus event");
 }
 
-// Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+// Assist pipelines of the active server, for managing them without the
+// webview's connection.
 #[tauri::command]
-fn open_app(window: tauri::Window) {
-    println!("Opening app...");
+async fn list_pipelines(
+    client: tauri::State<'_, HomeAssistantClient>,
+) -> Result<PipelineList, CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::list(&connection).await?)
+}
 
-    let current_url: String = window.url().to_string();
-    let mut url = Url::parse(&current_url).expect("failed to parse URL");
+// The pipeline with `id`, or the preferred pipeline without one.
+#[tauri::command]
+async fn get_pipeline(
+    client: tauri::State<'_, HomeAssistantClient>,
+    id: Option<String>,
+) -> Result<AssistPipeline, CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::get(&connection, id.as_deref()).await?)
+}
 
-    window.show().expect("failed to show the window");
-    window.set_focus().expect("failed to focus the window");
+#[tauri::command]
+async fn create_pipeline(
+    client: tauri::State<'_, HomeAssistantClient>,
+    pipeline: AssistPipelineMutableParams,
+) -> Result<AssistPipeline, CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::create(&connection, &pipeline).await?)
+}
 
-    url.set_path("/");
-    println!("Navigating to {}", url);
+#[tauri::command]
+async fn update_pipeline(
+    client: tauri::State<'_, HomeAssistantClient>,
+    id: String,
+    pipeline: AssistPipelineMutableParams,
+) -> Result<AssistPipeline, CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::update(&connection, &id, &pipeline).await?)
+}
 
-    window
-        .eval(&format!("window.location.href = '{}';", url))
-        .unwrap();
+#[tauri::command]
+async fn set_preferred_pipeline(
+    client: tauri::State<'_, HomeAssistantClient>,
+    id: String,
+) -> Result<(), CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::set_preferred(&connection, &id).await?)
 }
 
 #[tauri::command]
-fn open_settings(window: tauri::Window) {
-    println!("Opening settings...");
+async fn delete_pipeline(
+    client: tauri::State<'_, HomeAssistantClient>,
+    id: String,
+) -> Result<(), CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::delete(&connection, &id).await?)
+}
 
-    let current_url: String = window.url().to_string();
-    let mut url = Url::parse(&current_url).expect("failed to parse URL");
+// Languages that pipelines can be set up for.
+#[tauri::command]
+async fn list_pipeline_languages(
+    client: tauri::State<'_, HomeAssistantClient>,
+) -> Result<Vec<String>, CommandError> {
+    let connection = client.connection()?;
+    Ok(home_assistant::pipelines::languages(&connection).await?)
+}
 
-    window.show().expect("failed to show the window");
-    window.set_focus().expect("failed to focus the window");
+// Resolve a media path returned by Home Assistant, e.g. a TTS result, to a URL
+// the webview can load.
+#[tauri::command]
+fn resolve_media_url(
+    store: tauri::State<SettingsStore>,
+    path: String,
+) -> Result<String, CommandError> {
+    let settings = store.effective().settings;
+    Ok(settings.home_assistant()?.url(&path)?.to_string())
+}
 
-    url.set_path("/settings");
-    println!("Navigating to {}", url);
+// Transcribe recorded audio, mono 16-bit PCM, with the configured speech to
+// text backend.
+#[tauri::command]
+async fn transcribe_audio(
+    store: tauri::State<'_, SettingsStore>,
+    samples: Vec<i16>,
+    sample_rate: u32,
+) -> Result<String, CommandError> {
+    let (stt, network) =
+        store.read(|settings| (settings.stt.clone(), settings.network.clone()));
+    Ok(stt::transcribe(&stt, &network, &samples, sample_rate).await?)
+}
 
-    window
-        .eval(&format!("window.location.href = '{}';", url))
-        .unwrap();
+// Global shortcuts from settings and whether each could be registered.
+#[tauri::command]
+fn get_shortcut_registrations(
+    shortcuts: tauri::State<Shortcuts>,
+) -> Result<Vec<ShortcutRegistration>, CommandError> {
+    Ok(shortcuts.registrations())
 }
 
+// Download media from Home Assistant, e.g. a TTS result, as a data URL. This
+// goes through the proxy and CA certificates from settings, which the webview
+// knows nothing about.
 #[tauri::command]
-fn load_settings(app_handle: tauri::AppHandle) -> Result<Settings, CommandError> {
-    let settings_path: String = app_handle
-        .path_resolver()
-        .app_config_dir()
-        .unwrap()
-        .join("settings.json")
-        .to_str()
-        .unwrap()
+async fn fetch_media(
+    store: tauri::State<'_, SettingsStore>,
+    path: String,
+) -> Result<String, CommandError> {
+    let settings = store.effective().settings;
+    let home_assistant = settings.home_assistant()?;
+    let url = home_assistant.url(&path)?;
+
+    let client = http::client(&settings.network, MEDIA_TIMEOUT)?;
+    let request = client.get(url).bearer_auth(&home_assistant.access_token);
+    let response = http::send(request, "Home Assistant server").await?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
         .to_string();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| {
+            CommandError::new(
+                ErrorKind::Network,
+                format!("Could not download {}: {}", path, e),
+            )
+        })?;
 
-    println!("Loading settings from {}...", settings_path);
+    Ok(format!("data:{};base64,{}", content_type, BASE64.encode(bytes)))
+}
 
-    // If the directory doesn't exist, create it.
-    if !std::path::Path::new(&settings_path)
-        .parent()
-        .unwrap()
-        .exists()
-    {
-        std::fs::create_dir_all(std::path::Path::new(&settings_path).parent().unwrap()).unwrap();
+// Settings from the webview arrive as JSON, so invalid URLs and actions are
+// reported on their fields rather than failing the command as a whole.
+#[tauri::command]
+fn update_settings(
+    app_handle: tauri::AppHandle,
+    settings: serde_json::Value,
+) -> Result<(), CommandError> {
+    let settings = settings::parse(settings)?;
+    save_settings(&app_handle, settings)
+}
+
+#[tauri::command]
+fn validate_settings(settings: serde_json::Value) -> Result<Vec<FieldError>, CommandError> {
+    match settings::parse(settings) {
+        Ok(settings) => Ok(settings::validate(&settings)),
+        Err(errors) => Ok(errors),
     }
+}
 
-    // Convert the settings path to a Path.
-    let path: &std::path::Path = std::path::Path::new(&settings_path);
-
-    // Check if the file exists.
-    if !path.exists() {
-        // Create the file if it doesn't exist.
-        let file: File = File::create(path).unwrap();
-        // Create a new Settings struct.
-        let settings: Settings = Settings {
-            autostart: false,
-            home_assistant: HomeAssistantSettings {
-                access_token: "".to_string(),
-                host: "homeassistant.local".to_string(),
-                port: 8123,
-                ssl: false,
-            },
-            tray: Some(TraySettings {
-                double_click_action: "toggle_window".to_string(),
-            }),
-        };
-        // Serialize the Settings struct into JSON.
-        serde_json::to_writer_pretty(file, &settings).unwrap();
+// JSON Schema of settings.json, also printed by --print-settings-schema.
+#[tauri::command]
+fn get_settings_schema() -> Result<schemars::schema::RootSchema, CommandError> {
+    Ok(settings::schema())
+}
+
+#[tauri::command]
+fn export_settings(
+    store: tauri::State<SettingsStore>,
+    path: String,
+    passphrase: Option<String>,
+) -> Result<(), CommandError> {
+    println!("Exporting settings to {}...", path);
+
+    let bundle = settings::bundle::export(&store.get(), passphrase.as_deref())?;
+    let contents = serde_json::to_vec_pretty(&bundle)?;
+    std::fs::write(&path, contents).map_err(|e| {
+        CommandError::new(ErrorKind::Io, format!("Could not write {}: {}", path, e))
+    })?;
+
+    Ok(())
+}
+
+#[tauri::command]
+fn import_settings(
+    app_handle: tauri::AppHandle,
+    path: String,
+    passphrase: Option<String>,
+    dry_run: Option<bool>,
+) -> Result<ImportReport, CommandError> {
+    println!("Importing settings from {}...", path);
+
+    let contents = std::fs::read(&path).map_err(|e| {
+        CommandError::new(ErrorKind::Io, format!("Could not read {}: {}", path, e))
+    })?;
+    let bundle: Bundle = serde_json::from_slice(&contents)?;
+
+    let current = app_handle.state::<SettingsStore>().get();
+    let (merged, changed) = settings::bundle::import(&current, bundle, passphrase.as_deref())?;
+
+    let errors = settings::validate(&merged);
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let applied = !dry_run.unwrap_or(false);
+    if applied && !changed.is_empty() {
+        save_settings(&app_handle, merged)?;
+    }
+
+    Ok(ImportReport { changed, applied })
+}
+
+// Handle `--export-settings PATH` and `--import-settings PATH`, with an
+// optional `--passphrase-file PATH` (or HA_ASSIST_PASSPHRASE) for the access
+// tokens and `--dry-run` to only report what an import would change. Relative
+// paths are resolved against `cwd`, which for a second instance is not ours.
+fn settings_bundle_cli(
+    app_handle: &tauri::AppHandle,
+    args: &[String],
+    cwd: &std::path::Path,
+) -> Option<Result<String, CommandError>> {
+    let export_path = cli::value(args, "--export-settings");
+    let import_path = cli::value(args, "--import-settings");
+    if export_path.is_none() && import_path.is_none() {
+        return None;
     }
-    // Open the file in read-only mode.
-    let file: File = File::open(path).unwrap();
-    // Read the JSON contents of the file as an instance of `Settings`.
-    let mut settings: Settings = serde_json::from_reader(file)?;
-
-    if settings.tray.is_none() {
-        settings.tray = Some(TraySettings {
-            double_click_action: "toggle_window".to_string(),
-        });
+
+    let passphrase = match cli::value(args, "--passphrase-file") {
+        Some(path) => match std::fs::read_to_string(cwd.join(&path)) {
+            Ok(passphrase) => Some(passphrase.trim().to_string()),
+            Err(e) => {
+                return Some(Err(CommandError::new(
+                    ErrorKind::Io,
+                    format!("Could not read {}: {}", path, e),
+                )))
+            }
+        },
+        None => std::env::var("HA_ASSIST_PASSPHRASE").ok(),
+    };
+
+    if let Some(path) = export_path {
+        let path = cwd.join(path).to_string_lossy().to_string();
+        let store = app_handle.state::<SettingsStore>();
+        return Some(
+            export_settings(store, path.clone(), passphrase)
+                .map(|_| format!("Exported settings to {}", path)),
+        );
     }
 
-    Ok(settings)
+    let path = cwd.join(import_path?).to_string_lossy().to_string();
+    let dry_run = cli::has(args, "--dry-run");
+    Some(
+        import_settings(app_handle.clone(), path, passphrase, Some(dry_run)).map(|report| {
+            let verb = if report.applied { "Changed" } else { "Would change" };
+            if report.changed.is_empty() {
+                "No settings changed".to_string()
+            } else {
+                format!("{}:\n  {}", verb, report.changed.join("\n  "))
+            }
+        }),
+    )
 }
 
+// Rewrite the settings file as TOML, YAML or JSON. Returns the new path.
 #[tauri::command]
-fn update_settings(app_handle: tauri::AppHandle, settings: Settings) -> Result<(), CommandError> {
-    let settings_path: String = app_handle
-        .path_resolver()
-        .app_config_dir()
-        .unwrap()
-        .join("settings.json")
-        .to_str()
-        .unwrap()
-        .to_string();
+fn convert_settings(
+    store: tauri::State<SettingsStore>,
+    format: Format,
+) -> Result<String, CommandError> {
+    println!("Converting settings to {}...", format);
 
-    println!("Updating settings at {}...", settings_path);
+    let path = store
+        .convert(format)
+        .map_err(|e| CommandError::new(ErrorKind::Io, e))?;
 
-    // Open the file in write-only mode.
-    let file: File = File::create(settings_path).unwrap();
-    // Serialize the Settings struct into JSON.
-    serde_json::to_writer_pretty(file, &settings).unwrap();
+    Ok(path.display().to_string())
+}
+
+// Handle `--convert-settings FORMAT`.
+fn convert_settings_cli(
+    app_handle: &tauri::AppHandle,
+    args: &[String],
+) -> Option<Result<String, CommandError>> {
+    let format = match cli::value(args, "--convert-settings")?.parse::<Format>() {
+        Ok(format) => format,
+        Err(e) => return Some(Err(e.into())),
+    };
+
+    let store = app_handle.state::<SettingsStore>();
+    Some(convert_settings(store, format).map(|path| format!("Converted settings to {}", path)))
+}
+
+// Handle `pipelines list [--json]` and `pipelines set-preferred ID`, which
+// talk to Home Assistant directly and exit without starting the app.
+// `--profile NAME` picks the server, otherwise the active profile is used.
+fn pipelines_cli(
+    args: &[String],
+    config: &tauri::Config,
+    overrides: &Overrides,
+    profile: Option<&str>,
+) -> Option<Result<String, CommandError>> {
+    let args = cli::subcommand(args, "pipelines")?;
+    Some(run_pipelines_cli(args, config, overrides, profile))
+}
+
+fn run_pipelines_cli(
+    args: &[String],
+    config: &tauri::Config,
+    overrides: &Overrides,
+    profile: Option<&str>,
+) -> Result<String, CommandError> {
+    let directory = paths::config_dir_from(config).ok_or_else(|| {
+        CommandError::new(ErrorKind::Io, "Could not find the config directory")
+    })?;
+    // Secrets are read here rather than in the runtime below, which the
+    // keyring doesn't work in.
+    let (mut settings, _) = settings::load(&settings::find_settings_file(&directory))?;
+    if let Some(profile) = profile {
+        settings.activate_profile(profile)?;
+    }
+    let settings = overrides.apply(&settings).settings;
+    let home_assistant = settings.home_assistant()?;
+
+    tauri::async_runtime::block_on(pipelines_subcommand(
+        args,
+        home_assistant,
+        &settings.network,
+    ))
+}
+
+async fn pipelines_subcommand(
+    args: &[String],
+    home_assistant: &HomeAssistantSettings,
+    network: &NetworkSettings,
+) -> Result<String, CommandError> {
+    let set_preferred = match (args.first().map(String::as_str), args.get(1)) {
+        (Some("list"), _) => None,
+        (Some("set-preferred"), Some(id)) => Some(id),
+        _ => {
+            return Err(CommandError::new(
+                ErrorKind::Config,
+                "Usage: pipelines list [--json] | pipelines set-preferred ID",
+            ))
+        }
+    };
+
+    let connection = home_assistant::Connection::open(home_assistant, network).await?;
+    match set_preferred {
+        None => {
+            let list = home_assistant::pipelines::list(&connection).await?;
+            if cli::has(args, "--json") {
+                return Ok(serde_json::to_string_pretty(&list)?);
+            }
+            let lines: Vec<String> = list
+                .pipelines
+                .iter()
+                .map(|pipeline| {
+                    let preferred = list.preferred_pipeline.as_ref() == Some(&pipeline.id);
+                    format!(
+                        "{}\t{}\t{}{}",
+                        pipeline.id,
+                        pipeline.params.name,
+                        pipeline.params.language,
+                        if preferred { "\tpreferred" } else { "" }
+                    )
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        Some(id) => {
+            home_assistant::pipelines::set_preferred(&connection, id).await?;
+            Ok(format!("Set the preferred pipeline to {}", id))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProfileList {
+    active_profile: String,
+    profiles: Vec<String>,
+}
+
+#[tauri::command]
+fn list_profiles(store: tauri::State<SettingsStore>) -> Result<ProfileList, CommandError> {
+    let settings = store.get();
+
+    Ok(ProfileList {
+        active_profile: settings.active_profile,
+        profiles: settings.profiles.into_keys().collect(),
+    })
+}
+
+#[tauri::command]
+fn add_profile(
+    app_handle: tauri::AppHandle,
+    name: String,
+    home_assistant: HomeAssistantSettings,
+) -> Result<(), CommandError> {
+    let mut settings = app_handle.state::<SettingsStore>().get();
+    settings.add_profile(&name, home_assistant)?;
+    save_settings(&app_handle, settings)
+}
+
+#[tauri::command]
+fn remove_profile(app_handle: tauri::AppHandle, name: String) -> Result<(), CommandError> {
+    let store = app_handle.state::<SettingsStore>();
+    let mut settings = store.get();
+    let removed = settings.remove_profile(&name)?;
+    save_settings(&app_handle, settings)?;
+
+    if let Err(e) = settings::delete_secrets(&store.path(), &removed) {
+        log::warn!("Could not delete secrets of profile {}: {}", name, e);
+    }
 
     Ok(())
 }
 
 #[tauri::command]
-fn toggle_window(window: tauri::Window) {
-    let window_visible = window
-        .is_visible()
-        .expect("failed to check if the window is visible");
+fn activate_profile(app_handle: tauri::AppHandle, name: String) -> Result<(), CommandError> {
+    let mut settings = app_handle.state::<SettingsStore>().get();
+    if settings.active_profile == name {
+        return Ok(());
+    }
+
+    // The webview reconnects to the new server when it sees the change.
+    log::info!("Activating profile {}...", name);
+    settings.activate_profile(&name)?;
+    save_settings(&app_handle, settings)
+}
+
+#[tauri::command]
+fn toggle_window(window: tauri::Window) -> Result<(), CommandError> {
+    let window_visible = window.is_visible()?;
     println!("Window visible: {}", window_visible);
     if window_visible {
         println!("Hiding window...");
-        window.hide().expect("failed to hide the window");
+        window.hide()?;
+        Ok(())
     } else {
-        show_window_app(window.clone());
+        show_window_app(window)
     }
 }
 
 #[tauri::command]
-fn trigger_voice_pipeline(window: tauri::Window) {
-    if !window
-        .is_visible()
-        .expect("failed to check if the window is visible")
-    {
-        show_window_app(window.clone());
+fn trigger_voice_pipeline(window: tauri::Window) -> Result<(), CommandError> {
+    if !window.is_visible()? {
+        show_window_app(window.clone())?;
     }
 
     log::info!("Triggering voice pipeline...");
-    window
-        .emit("trigger-voice-pipeline", {})
-        .expect("failed to emit trigger-voice-pipeline event");
+    window.emit("trigger-voice-pipeline", {})?;
+
+    Ok(())
+}
+
+// Show the assist page, if needed, and send it an event.
+fn show_and_emit<S: Serialize + Clone>(
+    window: tauri::Window,
+    event: &str,
+    payload: S,
+) -> Result<(), CommandError> {
+    if !window.is_visible()? {
+        show_window_app(window.clone())?;
+    }
+
+    window.emit(event, payload)?;
+
+    Ok(())
+}
+
+// Run an action from the tray, a global shortcut or the command line. There
+// is no webview waiting for the result, so failures are logged.
+fn run_action(app_handle: &tauri::AppHandle, action: &Action) {
+    log::info!("Running action {}", action);
+
+    let Some(window) = app_handle.get_window("main") else {
+        log::error!("Could not run action {}: the main window is gone", action);
+        return;
+    };
+    let result = match action {
+        Action::None => Ok(()),
+        Action::ToggleWindow => toggle_window(window),
+        Action::TriggerVoicePipeline => trigger_voice_pipeline(window),
+        Action::OpenSettings => open_settings(window),
+        Action::NewConversation => {
+            app_handle.state::<HomeAssistantClient>().new_conversation();
+            show_and_emit(window, "new-conversation", ())
+        }
+        Action::QuickCommand(name) => {
+            let text = app_handle
+                .state::<SettingsStore>()
+                .read(|settings| settings.quick_commands.get(name).cloned());
+            match text {
+                Some(text) => show_and_emit(window, "quick-command", text),
+                None => Err(format!("Quick command \"{}\" does not exist", name).into()),
+            }
+        }
+        Action::SwitchPipeline(id) => show_and_emit(window, "switch-pipeline", id.clone()),
+    };
+
+    if let Err(e) = result {
+        log::error!("Could not run action {}: {}", action, e);
+    }
 }
 
 #[tauri::command]
-fn hide_window(window: tauri::Window) {
-    window.hide().expect("failed to hide the window");
+fn hide_window(window: tauri::Window) -> Result<(), CommandError> {
+    window.hide()?;
+
+    Ok(())
 }
 
 #[tauri::command]
-fn open_logs_directory(app_handle: tauri::AppHandle) {
-    let path: String = app_handle
-        .path_resolver()
-        .app_log_dir()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
+fn open_logs_directory(app_handle: tauri::AppHandle) -> Result<(), CommandError> {
+    let path = paths::log_dir(&app_handle)
+        .ok_or_else(|| CommandError::new(ErrorKind::Io, "Could not find the logs directory"))?;
 
-    println!("Opening logs directory at {}...", path);
+    println!("Opening logs directory at {}...", path.display());
 
     // Open file with default application
-    opener::open(path).unwrap();
+    opener::open(&path).map_err(|e| {
+        CommandError::new(
+            ErrorKind::Io,
+            format!("Could not open {}: {}", path.display(), e),
+        )
+    })?;
+
+    Ok(())
 }
 
 #[tauri::command]
-fn quit_application(window: tauri::Window) {
-    window.close().expect("failed to close the window");
+fn quit_application(window: tauri::Window) -> Result<(), CommandError> {
+    window.close()?;
     std::process::exit(0);
 }
 
@@ -256,39 +820,97 @@ fn main() {
 
     // Check for CLI arguments (for triggering actions via KDE shortcuts on Wayland)
     let args: Vec<String> = std::env::args().collect();
-    let trigger_voice = args.iter().any(|arg| arg == "--trigger-voice");
 
-    let tray_menu: SystemTrayMenu = SystemTrayMenu::new()
-        .add_item(CustomMenuItem::new(
-            "toggle_window".to_string(),
-            "Show/Hide Window (Ctrl+Alt+A)",
-        ))
-        .add_item(CustomMenuItem::new(
-            "trigger_voice_pipeline".to_string(),
-            "Trigger Voice Pipeline (Ctrl+Shift+A)",
-        ))
-        .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(CustomMenuItem::new("open_settings".to_string(), "Settings"))
-        .add_item(CustomMenuItem::new(
-            "open_logs_directory".to_string(),
-            "Open Logs",
-        ))
-        .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(CustomMenuItem::new(
-            "check_for_updates".to_string(),
-            format!("Check for Updates ({})", env!("CARGO_PKG_VERSION")),
-        ))
-        .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(CustomMenuItem::new("quit_application".to_string(), "Quit"));
+    // --print-settings-schema prints the JSON Schema of settings.json and exits
+    // without starting the app
+    if cli::has(&args, "--print-settings-schema") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&settings::schema()).unwrap()
+        );
+        return;
+    }
+
+    // --portable, or a `portable` file next to the executable, keeps settings
+    // and logs in a directory next to the executable
+    if let Some(directory) = paths::init(&args) {
+        println!("Portable mode, using {}", directory.display());
+    }
+
+    let trigger_voice = cli::has(&args, "--trigger-voice");
+
+    // --action ACTION runs any action, e.g. `--action quick_command:NAME`
+    let action = match cli::value(&args, "--action").map(|action| action.parse::<Action>()) {
+        Some(Ok(action)) => Some(action),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    let profile = cli::value(&args, "--profile");
+
+    // HA_ASSIST_* environment variables and --host, --port, --ssl and
+    // --token-file override settings.json without being saved to it
+    let overrides = match Overrides::from_env_and_args(&args) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let context = tauri::generate_context!();
+
+    // `pipelines ...` manages Assist pipelines and exits without starting the
+    // app, so it works from scripts while the app is running
+    match pipelines_cli(&args, context.config(), &overrides, profile.as_deref()) {
+        Some(Ok(output)) => {
+            println!("{}", output);
+            return;
+        }
+        Some(Err(e)) => {
+            eprintln!("{}", e.message);
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
+    // The menu is rebuilt from the real settings once the app is set up.
+    let tray_menu = tray::build_menu(&Settings::default(), &[], &ConnectionStatus::default());
 
     tauri::Builder::default()
-        .plugin(tauri_plugin_single_instance::init(|app, argv, _cwd| {
+        .plugin(tauri_plugin_single_instance::init(|app, argv, cwd| {
             log::info!("Single instance triggered with args: {:?}", argv);
 
+            // Check if --export-settings, --import-settings or --convert-settings are present
+            match settings_bundle_cli(app, &argv, std::path::Path::new(&cwd))
+                .or_else(|| convert_settings_cli(app, &argv))
+            {
+                Some(Ok(message)) => log::info!("{}", message),
+                Some(Err(e)) => log::error!("{}", e.message),
+                None => {}
+            }
+
+            // Check if --profile flag is present
+            if let Some(profile) = cli::value(&argv, "--profile") {
+                if let Err(e) = activate_profile(app.clone(), profile) {
+                    log::error!("Could not activate profile: {}", e.message);
+                }
+            }
+
             // Check if --trigger-voice flag is present
-            if argv.iter().any(|arg| arg == "--trigger-voice") {
-                let window = app.get_window("main").unwrap();
-                trigger_voice_pipeline(window);
+            if cli::has(&argv, "--trigger-voice") {
+                run_action(app, &Action::TriggerVoicePipeline);
+            }
+
+            // Check if --action flag is present
+            if let Some(action) = cli::value(&argv, "--action") {
+                match action.parse::<Action>() {
+                    Ok(action) => run_action(app, &action),
+                    Err(e) => log::error!("{}", e),
+                }
             }
         }))
         .plugin(tauri_plugin_autostart::init(
@@ -297,52 +919,68 @@ fn main() {
         ))
         .plugin(
             tauri_plugin_log::Builder::default()
-                .targets([LogTarget::LogDir, LogTarget::Stdout, LogTarget::Webview])
+                .targets([
+                    paths::portable_log_dir().map_or(LogTarget::LogDir, LogTarget::Folder),
+                    LogTarget::Stdout,
+                    LogTarget::Webview,
+                ])
                 .build(),
         )
         .on_window_event(|event: tauri::GlobalWindowEvent| match event.event() {
             tauri::WindowEvent::CloseRequested { api, .. } => {
-                event.window().hide().unwrap();
+                if let Err(e) = event.window().hide() {
+                    log::error!("Could not hide the window: {}", e);
+                }
                 api.prevent_close();
             }
+            tauri::WindowEvent::Moved(_) | tauri::WindowEvent::Resized(_) => {
+                window_state::remember(event.window());
+            }
             _ => {}
         })
+        .manage(Shortcuts::default())
+        .manage(HomeAssistantClient::default())
         .system_tray(SystemTray::new().with_menu(tray_menu))
         .on_system_tray_event(
             |app: &tauri::AppHandle, event: tauri::SystemTrayEvent| match event {
+                tauri::SystemTrayEvent::LeftClick { .. } => {
+                    let action = app
+                        .state::<SettingsStore>()
+                        .read(|settings| settings.tray.left_click.clone());
+                    run_action(app, &action);
+                }
                 tauri::SystemTrayEvent::DoubleClick { .. } => {
-                    let settings = load_settings(app.clone()).unwrap();
-
-                    let action = if settings.tray.is_some() {
-                        settings.tray.unwrap().double_click_action
-                    } else {
-                        "toggle_window".to_string()
-                    };
-
-                    let window: tauri::Window = app.get_window("main").unwrap();
-                    match action.as_str() {
-                        "toggle_window" => {
-                            toggle_window(window);
-                        }
-                        "trigger_voice_pipeline" => {
-                            trigger_voice_pipeline(window);
-                        }
-                        _ => {}
-                    }
+                    let action = app
+                        .state::<SettingsStore>()
+                        .read(|settings| settings.tray.double_click.clone());
+                    run_action(app, &action);
                 }
                 tauri::SystemTrayEvent::MenuItemClick { id, .. } => {
-                    let window: tauri::Window = app.get_window("main").unwrap();
                     match id.as_str() {
-                        "toggle_window" => toggle_window(window),
-                        "trigger_voice_pipeline" => trigger_voice_pipeline(window),
-                        "open_settings" => open_settings(window),
-                        "open_logs_directory" => open_logs_directory(app.clone()),
-                        "check_for_updates" => open_browser(
-                            "https://github.com/timmo001/home-assistant-assist-desktop/releases",
-                        )
-                        .unwrap(),
-                        "quit_application" => quit_application(window),
-                        _ => {}
+                        "open_logs_directory" => {
+                            if let Err(e) = open_logs_directory(app.clone()) {
+                                log::error!("{}", e);
+                            }
+                        }
+                        "check_for_updates" => {
+                            if let Err(e) = open_browser(
+                                "https://github.com/timmo001/home-assistant-assist-desktop/releases",
+                            ) {
+                                log::error!("Could not open the releases page: {}", e);
+                            }
+                        }
+                        "quit_application" => app.exit(0),
+                        id if id.starts_with(tray::PROFILE_ITEM_PREFIX) => {
+                            let name = &id[tray::PROFILE_ITEM_PREFIX.len()..];
+                            if let Err(e) = activate_profile(app.clone(), name.to_string()) {
+                                log::error!("Could not activate profile: {}", e.message);
+                            }
+                        }
+                        // Everything else is an action in its string form
+                        id => match id.parse::<Action>() {
+                            Ok(action) => run_action(app, &action),
+                            Err(e) => log::warn!("Unhandled tray menu item {}: {}", id, e),
+                        },
                     }
                 }
                 _ => {}
@@ -352,7 +990,32 @@ fn main() {
             open_app,
             open_settings,
             load_settings,
+            get_effective_settings,
+            get_home_assistant_urls,
+            get_connection_status,
+            test_connection,
+            run_pipeline,
+            list_pipelines,
+            get_pipeline,
+            create_pipeline,
+            update_pipeline,
+            set_preferred_pipeline,
+            delete_pipeline,
+            list_pipeline_languages,
+            resolve_media_url,
+            fetch_media,
+            get_shortcut_registrations,
+            transcribe_audio,
             update_settings,
+            validate_settings,
+            get_settings_schema,
+            export_settings,
+            import_settings,
+            convert_settings,
+            list_profiles,
+            add_profile,
+            remove_profile,
+            activate_profile,
             toggle_window,
             trigger_voice_pipeline,
             hide_window,
@@ -371,12 +1034,79 @@ fn main() {
                 log::info!("Webkit Wayland compatibility flags enabled (GTK_USE_PORTAL=1)");
             }
 
-            let window = app.get_window("main").unwrap();
+            // Load settings once, everything else reads them from memory
+            let settings_path = settings::settings_path(&app.handle())?;
+            let store = SettingsStore::load(settings_path, overrides)?;
+            let store_path = store.path();
+            app.manage(store);
+
+            // Where the window was on each monitor, saved in settings
+            app.manage(window_state::start(app.handle()));
+
+            // Text given while Home Assistant was unreachable, replayed once
+            // it is back
+            let data_dir = paths::data_dir(&app.handle())
+                .ok_or("Could not find the data directory")?;
+            app.manage(CommandQueue::load(data_dir.join("queue.json")));
+
+            // Export, import or convert settings from the command line, then exit
+            let cwd = std::env::current_dir().unwrap_or_default();
+            match settings_bundle_cli(&app.handle(), &args, &cwd)
+                .or_else(|| convert_settings_cli(&app.handle(), &args))
+            {
+                Some(Ok(message)) => {
+                    println!("{}", message);
+                    std::process::exit(0);
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e.message);
+                    for error in e.errors {
+                        eprintln!("  {}: {}", error.field, error.message);
+                    }
+                    std::process::exit(1);
+                }
+                None => {}
+            }
+
+            // If --profile was passed, switch to it before anything connects
+            if let Some(profile) = profile.clone() {
+                log::info!("CLI: Activating profile {} from --profile flag", profile);
+                if let Err(e) = activate_profile(app.handle(), profile) {
+                    log::error!("Could not activate profile: {}", e.message);
+                }
+            }
+
+            // Register global shortcuts, then show them in the tray
+            let settings = app.state::<SettingsStore>().effective().settings;
+            shortcuts::register(&app.handle(), &settings.shortcuts);
+            tray::refresh(&app.handle(), &settings);
+
+            // Connect to Home Assistant for anything done without the window
+            home_assistant::connect(&app.handle());
+
+            // Pick up edits made to settings.json while the app is running
+            let app_handle = app.handle();
+            settings::watch(store_path, move || {
+                match app_handle.state::<SettingsStore>().reload() {
+                    Ok(Some(_)) => {
+                        log::info!("Settings changed on disk, applying...");
+                        settings_changed(&app_handle, ChangeSource::File);
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Could not reload settings: {}", e),
+                }
+            });
 
             // If --trigger-voice flag was passed, trigger the voice pipeline
             if trigger_voice {
                 log::info!("CLI: Triggering voice pipeline from --trigger-voice flag");
-                trigger_voice_pipeline(window.clone());
+                run_action(&app.handle(), &Action::TriggerVoicePipeline);
+            }
+
+            // If --action was passed, run it
+            if let Some(action) = &action {
+                log::info!("CLI: Running action {} from --action flag", action);
+                run_action(&app.handle(), action);
             }
 
             // Linux: Auto-grant microphone/camera permissions
@@ -387,6 +1117,7 @@ fn main() {
                 use webkit2gtk::WebViewExt;
                 use webkit2gtk::SettingsExt;
 
+                let window = app.get_window("main").unwrap();
                 window.with_webview(|webview| {
                     let wv = webview.inner();
 
@@ -413,27 +1144,8 @@ fn main() {
                 }).unwrap();
             }
 
-            // Try to register global shortcuts, but don't panic if they fail
-            // (might already be in use by another app)
-            if let Err(e) = app.global_shortcut_manager()
-                .register("Ctrl+Alt+A", move || {
-                    toggle_window(window.clone());
-                })
-            {
-                log::warn!("Could not register Ctrl+Alt+A shortcut: {}", e);
-            }
-
-            let window = app.get_window("main").unwrap();
-            if let Err(e) = app.global_shortcut_manager()
-                .register("Ctrl+Shift+A", move || {
-                    trigger_voice_pipeline(window.clone());
-                })
-            {
-                log::warn!("Could not register Ctrl+Shift+A shortcut: {}", e);
-            }
-
             Ok(())
         })
-        .run(tauri::generate_context!())
+        .run(context)
         .expect("error while running tauri application");
 }
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// A file with this name next to the executable switches on portable mode,
+// as does `--portable`.
+const PORTABLE_MARKER: &str = "portable";
+
+// In portable mode everything is kept in this directory next to the
+// executable, e.g. on a USB drive.
+const PORTABLE_DIRECTORY: &str = "data";
+
+static PORTABLE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+// Decide whether to run in portable mode. Called once at startup, before
+// anything reads or writes files.
+pub fn init(args: &[String]) -> Option<&'static Path> {
+    PORTABLE
+        .get_or_init(|| detect_portable(&executable_directory()?, args))
+        .as_deref()
+}
+
+// The portable data directory next to the executable in `directory`, if
+// portable mode is switched on.
+fn detect_portable(directory: &Path, args: &[String]) -> Option<PathBuf> {
+    if crate::cli::has(args, "--portable") || directory.join(PORTABLE_MARKER).exists() {
+        Some(directory.join(PORTABLE_DIRECTORY))
+    } else {
+        None
+    }
+}
+
+// Where files of one kind, e.g. `config`, go in portable mode. Without a
+// portable directory the system's usual one is used.
+fn redirect(portable: Option<&Path>, kind: &str) -> Option<PathBuf> {
+    portable.map(|directory| directory.join(kind))
+}
+
+pub fn is_portable() -> bool {
+    portable_directory().is_some()
+}
+
+fn portable_directory() -> Option<&'static Path> {
+    PORTABLE.get().and_then(Option::as_deref)
+}
+
+// The directory of the executable. For an AppImage that is the directory of
+// the AppImage file, as the executable itself is in a read-only mount.
+fn executable_directory() -> Option<PathBuf> {
+    let executable = match std::env::var_os("APPIMAGE") {
+        Some(appimage) => PathBuf::from(appimage),
+        None => std::env::current_exe().ok()?,
+    };
+    executable.parent().map(Path::to_path_buf)
+}
+
+// Where settings and secrets are kept.
+pub fn config_dir(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    config_dir_from(&app_handle.config())
+}
+
+// `config_dir` for before there is an app handle, e.g. for command line
+// subcommands that run without starting the app.
+pub fn config_dir_from(config: &tauri::Config) -> Option<PathBuf> {
+    redirect(portable_directory(), "config").or_else(|| tauri::api::path::app_config_dir(config))
+}
+
+// Where the app keeps state that isn't settings, e.g. queued commands. In
+// portable mode that is the portable directory itself, next to `config` and
+// `logs`.
+pub fn data_dir(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    portable_directory()
+        .map(Path::to_path_buf)
+        .or_else(|| app_handle.path_resolver().app_data_dir())
+}
+
+// Where logs are written.
+pub fn log_dir(app_handle: &tauri::AppHandle) -> Option<PathBuf> {
+    portable_log_dir().or_else(|| app_handle.path_resolver().app_log_dir())
+}
+
+// The log plugin is set up before there is an app handle, so it is given the
+// portable log directory directly.
+pub fn portable_log_dir() -> Option<PathBuf> {
+    redirect(portable_directory(), "logs")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn is_not_portable_by_default() {
+        let directory = tempfile::tempdir().unwrap();
+        assert_eq!(detect_portable(directory.path(), &args(&["app"])), None);
+    }
+
+    #[test]
+    fn is_portable_with_the_flag() {
+        let directory = tempfile::tempdir().unwrap();
+        assert_eq!(
+            detect_portable(directory.path(), &args(&["app", "--portable"])),
+            Some(directory.path().join("data"))
+        );
+    }
+
+    #[test]
+    fn is_portable_with_the_marker_file() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("portable"), "").unwrap();
+        assert_eq!(
+            detect_portable(directory.path(), &args(&["app"])),
+            Some(directory.path().join("data"))
+        );
+    }
+
+    #[test]
+    fn redirects_directories_in_portable_mode() {
+        let portable = Path::new("/media/usb/data");
+        assert_eq!(
+            redirect(Some(portable), "config"),
+            Some(portable.join("config"))
+        );
+        assert_eq!(
+            redirect(Some(portable), "logs"),
+            Some(portable.join("logs"))
+        );
+        assert_eq!(redirect(None, "config"), None);
+    }
+}
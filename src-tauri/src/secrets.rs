@@ -0,0 +1,635 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Keyring service name, matching the bundle identifier.
+const SERVICE: &str = "dev.timmo.home-assistant-assist-desktop";
+
+// References kept in settings say where the secret lives.
+const KEYRING_PREFIX: &str = "keyring:";
+const FILE_PREFIX: &str = "file:";
+
+const NONCE_LENGTH: usize = 12;
+
+// Secrets read or written since the app started, by directory and reference.
+// Saving settings compares with these, so unchanged secrets aren't written to
+// the keyring on every save.
+static KNOWN: Mutex<BTreeMap<(PathBuf, String), String>> = Mutex::new(BTreeMap::new());
+
+// Stores secrets in the system keyring (the freedesktop Secret Service over
+// D-Bus on Linux), falling back to a file in the config directory when no
+// keyring is available, e.g. on minimal window managers or headless sessions.
+//
+// The file is encrypted with a key kept right next to it, so this only keeps
+// tokens out of plain sight, e.g. when grepping or sharing the config
+// directory. Anyone who can read the directory can decrypt them.
+pub struct SecretStore {
+    directory: PathBuf,
+}
+
+impl SecretStore {
+    pub fn new(directory: &Path) -> Self {
+        SecretStore {
+            directory: directory.to_path_buf(),
+        }
+    }
+
+    // Store a secret under `id` and return the reference to keep in settings.
+    pub fn set(&self, id: &str, secret: &str) -> Result<String, String> {
+        let reference = self.write(id, secret)?;
+        self.remember(&reference, secret);
+        Ok(reference)
+    }
+
+    // Like `set`, but keeps `reference` without writing anything if it already
+    // holds `secret` under `id`.
+    pub fn update(
+        &self,
+        reference: Option<&str>,
+        id: &str,
+        secret: &str,
+    ) -> Result<String, String> {
+        if let Some(reference) = reference {
+            let same_id = [KEYRING_PREFIX, FILE_PREFIX]
+                .iter()
+                .any(|prefix| reference.strip_prefix(prefix) == Some(id));
+            if same_id && self.known(reference).as_deref() == Some(secret) {
+                return Ok(reference.to_string());
+            }
+        }
+        self.set(id, secret)
+    }
+
+    // The value of `reference` if it was read or written since the app
+    // started.
+    pub fn known(&self, reference: &str) -> Option<String> {
+        KNOWN
+            .lock()
+            .unwrap()
+            .get(&(self.directory.clone(), reference.to_string()))
+            .cloned()
+    }
+
+    fn remember(&self, reference: &str, secret: &str) {
+        KNOWN.lock().unwrap().insert(
+            (self.directory.clone(), reference.to_string()),
+            secret.to_string(),
+        );
+    }
+
+    fn forget(&self, reference: &str) {
+        KNOWN
+            .lock()
+            .unwrap()
+            .remove(&(self.directory.clone(), reference.to_string()));
+    }
+
+    fn write(&self, id: &str, secret: &str) -> Result<String, String> {
+        // A portable copy keeps its secrets with its settings, so they move
+        // with it and don't replace those of an installed copy in the keyring.
+        if !crate::paths::is_portable() {
+            match keyring::Entry::new(SERVICE, id).and_then(|entry| entry.set_password(secret)) {
+                Ok(()) => {
+                    // Don't leave an older copy behind in the fallback file.
+                    self.remove_from_file(id)?;
+                    self.forget(&format!("{}{}", FILE_PREFIX, id));
+                    return Ok(format!("{}{}", KEYRING_PREFIX, id));
+                }
+                Err(e) => log::warn!("System keyring unavailable, using encrypted file: {}", e),
+            }
+        }
+
+        self.write_to_file(id, secret)
+    }
+
+    // Store a secret in the fallback file only, returning its reference. Also
+    // used by tests, so they never touch the user's keyring.
+    pub(crate) fn write_to_file(&self, id: &str, secret: &str) -> Result<String, String> {
+        let mut secrets = self.read_file()?;
+        secrets.insert(id.to_string(), self.encrypt(secret)?);
+        self.write_file(&secrets)?;
+
+        Ok(format!("{}{}", FILE_PREFIX, id))
+    }
+
+    pub fn get(&self, reference: &str) -> Result<String, String> {
+        let secret = self.read(reference)?;
+        self.remember(reference, &secret);
+        Ok(secret)
+    }
+
+    fn read(&self, reference: &str) -> Result<String, String> {
+        if let Some(id) = reference.strip_prefix(KEYRING_PREFIX) {
+            keyring::Entry::new(SERVICE, id)
+                .and_then(|entry| entry.get_password())
+                .map_err(|e| format!("Could not read secret {} from keyring: {}", id, e))
+        } else if let Some(id) = reference.strip_prefix(FILE_PREFIX) {
+            let secrets = self.read_file()?;
+            let encrypted = secrets.get(id).ok_or_else(|| {
+                format!("Secret {} not found in {}", id, self.file_path().display())
+            })?;
+            self.decrypt(encrypted)
+        } else {
+            Err(format!("Unknown secret reference: {}", reference))
+        }
+    }
+
+    pub fn delete(&self, reference: &str) -> Result<(), String> {
+        self.forget(reference);
+        if let Some(id) = reference.strip_prefix(KEYRING_PREFIX) {
+            match keyring::Entry::new(SERVICE, id).and_then(|entry| entry.delete_credential()) {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(format!(
+                    "Could not delete secret {} from keyring: {}",
+                    id, e
+                )),
+            }
+        } else if let Some(id) = reference.strip_prefix(FILE_PREFIX) {
+            self.remove_from_file(id)
+        } else {
+            Err(format!("Unknown secret reference: {}", reference))
+        }
+    }
+
+    fn file_path(&self) -> PathBuf {
+        self.directory.join("secrets.json")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.directory.join("secrets.key")
+    }
+
+    fn read_file(&self) -> Result<BTreeMap<String, String>, String> {
+        match std::fs::read(self.file_path()) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn write_file(&self, secrets: &BTreeMap<String, String>) -> Result<(), String> {
+        let contents = serde_json::to_vec_pretty(secrets).map_err(|e| e.to_string())?;
+        crate::settings::write_atomically_private(&self.file_path(), &contents)
+    }
+
+    fn remove_from_file(&self, id: &str) -> Result<(), String> {
+        let mut secrets = self.read_file()?;
+        if secrets.remove(id).is_some() {
+            self.write_file(&secrets)?;
+        }
+        Ok(())
+    }
+
+    // The key for the fallback file, created on first use. Like the file, it
+    // is only readable by the current user on Unix.
+    fn key(&self) -> Result<Key, String> {
+        let key_path = self.key_path();
+        match std::fs::read(&key_path) {
+            Ok(key) if key.len() == 32 => return Ok(*Key::from_slice(&key)),
+            Ok(_) => return Err(format!("Invalid secret key in {}", key_path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.to_string()),
+        }
+
+        log::info!("Creating secret key at {}", key_path.display());
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&key_path).map_err(|e| e.to_string())?;
+        std::io::Write::write_all(&mut file, &key).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+
+        Ok(key)
+    }
+
+    fn encrypt(&self, secret: &str) -> Result<String, String> {
+        encrypt(&self.key()?, secret)
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Result<String, String> {
+        decrypt(&self.key()?, encrypted)
+            .map_err(|_| "Could not decrypt secret, the key may have changed".to_string())
+    }
+}
+
+// Encrypt a secret, returning base64 of the random nonce followed by the
+// ciphertext.
+pub fn encrypt(key: &Key, secret: &str) -> Result<String, String> {
+    let cipher = ChaCha20Poly1305::new(key);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, secret.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&ciphertext);
+    Ok(BASE64.encode(data))
+}
+
+pub fn decrypt(key: &Key, encrypted: &str) -> Result<String, String> {
+    let data = BASE64.decode(encrypted).map_err(|e| e.to_string())?;
+    if data.len() < NONCE_LENGTH {
+        return Err("Encrypted secret is too short".to_string());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+
+    let cipher = ChaCha20Poly1305::new(key);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Could not decrypt secret".to_string())?;
+
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+// Only on Linux, where the keyring is the Secret Service.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::io::BufRead;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::Arc;
+    use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Type, Value};
+
+    const COLLECTION: &str = "/org/freedesktop/secrets/collection/default";
+
+    // An item of the mock keyring, with its secret in plaintext.
+    struct MockItem {
+        attributes: HashMap<String, String>,
+        secret: Vec<u8>,
+    }
+
+    #[derive(Default)]
+    struct MockKeyring {
+        // AES keys by session path.
+        sessions: HashMap<String, [u8; 16]>,
+        // By item path. Deleted items are removed here but stay on the bus.
+        items: BTreeMap<String, MockItem>,
+        writes: usize,
+    }
+
+    type Shared = Arc<Mutex<MockKeyring>>;
+
+    #[derive(Serialize, Deserialize, Type)]
+    struct Secret {
+        session: OwnedObjectPath,
+        parameters: Vec<u8>,
+        value: Vec<u8>,
+        content_type: String,
+    }
+
+    fn path(path: String) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(path).unwrap()
+    }
+
+    fn encrypt_secret(key: &[u8; 16], session: OwnedObjectPath, secret: &[u8]) -> Secret {
+        use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+        let iv: [u8; 16] = rand::random();
+        let value = cbc::Encryptor::<aes::Aes128>::new(key.into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(secret);
+        Secret {
+            session,
+            parameters: iv.to_vec(),
+            value,
+            content_type: "text/plain".to_string(),
+        }
+    }
+
+    fn decrypt_secret(keyring: &MockKeyring, secret: &Secret) -> Vec<u8> {
+        use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+        let key = keyring.sessions[secret.session.as_str()];
+        let iv: [u8; 16] = secret.parameters.as_slice().try_into().unwrap();
+        cbc::Decryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&secret.value)
+            .unwrap()
+    }
+
+    fn search(keyring: &MockKeyring, attributes: &HashMap<String, String>) -> Vec<OwnedObjectPath> {
+        keyring
+            .items
+            .iter()
+            .filter(|(_, item)| {
+                attributes
+                    .iter()
+                    .all(|(name, value)| item.attributes.get(name) == Some(value))
+            })
+            .map(|(item_path, _)| path(item_path.clone()))
+            .collect()
+    }
+
+    struct MockService(Shared);
+
+    #[zbus::interface(name = "org.freedesktop.Secret.Service")]
+    impl MockService {
+        // The client's private key is x and its public key 2^x mod p. With 1
+        // as the private key here, our public key is the generator 2 and the
+        // shared secret 2^x mod p, i.e. the client's public key, so no big
+        // number arithmetic is needed.
+        fn open_session(
+            &self,
+            algorithm: &str,
+            input: Value<'_>,
+        ) -> zbus::fdo::Result<(OwnedValue, OwnedObjectPath)> {
+            use hkdf::Hkdf;
+            use sha2::Sha256;
+
+            if algorithm != "dh-ietf1024-sha256-aes128-cbc-pkcs7" {
+                return Err(zbus::fdo::Error::NotSupported(algorithm.to_string()));
+            }
+            let client_public = Vec::<u8>::try_from(input)
+                .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+            let mut shared = vec![0; 128 - client_public.len()];
+            shared.extend_from_slice(&client_public);
+            let mut key = [0; 16];
+            Hkdf::<Sha256>::new(None, &shared)
+                .expand(&[], &mut key)
+                .unwrap();
+
+            let mut keyring = self.0.lock().unwrap();
+            let session = format!(
+                "/org/freedesktop/secrets/session/{}",
+                keyring.sessions.len()
+            );
+            keyring.sessions.insert(session.clone(), key);
+            let output = OwnedValue::try_from(Value::from(vec![2u8])).unwrap();
+            Ok((output, path(session)))
+        }
+
+        fn search_items(
+            &self,
+            attributes: HashMap<String, String>,
+        ) -> (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) {
+            (search(&self.0.lock().unwrap(), &attributes), Vec::new())
+        }
+
+        fn read_alias(&self, _name: &str) -> OwnedObjectPath {
+            path(COLLECTION.to_string())
+        }
+
+        fn unlock(&self, objects: Vec<OwnedObjectPath>) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            (objects, path("/".to_string()))
+        }
+    }
+
+    struct MockCollection(Shared);
+
+    #[zbus::interface(name = "org.freedesktop.Secret.Collection")]
+    impl MockCollection {
+        fn search_items(&self, attributes: HashMap<String, String>) -> Vec<OwnedObjectPath> {
+            search(&self.0.lock().unwrap(), &attributes)
+        }
+
+        async fn create_item(
+            &self,
+            #[zbus(object_server)] server: &zbus::ObjectServer,
+            mut properties: HashMap<String, OwnedValue>,
+            secret: Secret,
+            _replace: bool,
+        ) -> zbus::fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let attributes = properties
+                .remove("org.freedesktop.Secret.Item.Attributes")
+                .and_then(|value| HashMap::<String, String>::try_from(value).ok())
+                .ok_or_else(|| zbus::fdo::Error::InvalidArgs("No attributes".to_string()))?;
+
+            let item_path = {
+                let mut keyring = self.0.lock().unwrap();
+                let secret = decrypt_secret(&keyring, &secret);
+                keyring.writes += 1;
+                let item_path = format!("{}/{}", COLLECTION, keyring.writes);
+                keyring
+                    .items
+                    .insert(item_path.clone(), MockItem { attributes, secret });
+                item_path
+            };
+            server
+                .at(
+                    item_path.clone(),
+                    MockItemObject(self.0.clone(), item_path.clone()),
+                )
+                .await?;
+            Ok((path(item_path), path("/".to_string())))
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+    }
+
+    struct MockItemObject(Shared, String);
+
+    #[zbus::interface(name = "org.freedesktop.Secret.Item")]
+    impl MockItemObject {
+        fn get_secret(&self, session: ObjectPath<'_>) -> zbus::fdo::Result<Secret> {
+            let keyring = self.0.lock().unwrap();
+            let item = keyring
+                .items
+                .get(&self.1)
+                .ok_or_else(|| zbus::fdo::Error::UnknownObject(self.1.clone()))?;
+            let key = keyring.sessions[session.as_str()];
+            Ok(encrypt_secret(&key, session.into(), &item.secret))
+        }
+
+        fn set_secret(&self, secret: Secret) {
+            let mut keyring = self.0.lock().unwrap();
+            let plaintext = decrypt_secret(&keyring, &secret);
+            keyring.writes += 1;
+            if let Some(item) = keyring.items.get_mut(&self.1) {
+                item.secret = plaintext;
+            }
+        }
+
+        fn delete(&self) -> OwnedObjectPath {
+            self.0.lock().unwrap().items.remove(&self.1);
+            path("/".to_string())
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+    }
+
+    // A session bus of our own, so the test never touches the user's keyring.
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start_bus(directory: &Path) -> (Bus, String) {
+        let mut child = Command::new("dbus-daemon")
+            .arg("--session")
+            .arg("--nofork")
+            .arg("--print-address")
+            .arg(format!(
+                "--address=unix:path={}",
+                directory.join("bus").display()
+            ))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed to test the keyring, install dbus");
+        let mut address = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        (Bus(child), address.trim().to_string())
+    }
+
+    // Serve the mock keyring on the bus from a thread with its own runtime, as
+    // keyring blocks on one of its own.
+    fn serve_keyring(address: String) -> Shared {
+        let keyring = Shared::default();
+        let (ready, wait) = std::sync::mpsc::channel();
+        let shared = keyring.clone();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let _connection = zbus::connection::Builder::address(address.as_str())
+                    .unwrap()
+                    .name("org.freedesktop.secrets")
+                    .unwrap()
+                    .serve_at("/org/freedesktop/secrets", MockService(shared.clone()))
+                    .unwrap()
+                    .serve_at(COLLECTION, MockCollection(shared))
+                    .unwrap()
+                    .build()
+                    .await
+                    .unwrap();
+                ready.send(()).unwrap();
+                std::future::pending::<()>().await;
+            });
+        });
+        wait.recv().unwrap();
+        keyring
+    }
+
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    // The test directory, passed to the steps of `stores_secrets_in_keyring_or_file`.
+    const DIRECTORY_VARIABLE: &str = "HA_ASSIST_TEST_SECRETS_DIRECTORY";
+
+    // keyring only connects to the session bus given in the environment. So
+    // that no test changes the environment of the others, each step runs in
+    // a process of its own started with the bus address it should use.
+    #[test]
+    fn stores_secrets_in_keyring_or_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_bus, address) = start_bus(directory.path());
+
+        let missing = format!("unix:path={}", directory.path().join("missing").display());
+        run_step("without_keyring", &missing, directory.path());
+        run_step("with_keyring", &address, directory.path());
+    }
+
+    fn run_step(name: &str, address: &str, directory: &Path) {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .arg(format!("secrets::tests::{}", name))
+            .args(["--exact", "--ignored", "--test-threads=1"])
+            .env("DBUS_SESSION_BUS_ADDRESS", address)
+            .env(DIRECTORY_VARIABLE, directory)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Also fails if the name matched no test at all.
+        assert!(
+            output.status.success() && stdout.contains("1 passed"),
+            "step {} failed:\n{}{}",
+            name,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // The directory of the test running this step, if it is one.
+    fn step_directory() -> Option<PathBuf> {
+        std::env::var_os(DIRECTORY_VARIABLE).map(PathBuf::from)
+    }
+
+    const ID: &str = "profiles/default/access_token";
+
+    #[test]
+    #[ignore = "a step of stores_secrets_in_keyring_or_file"]
+    fn without_keyring() {
+        let Some(directory) = step_directory() else {
+            return;
+        };
+        let store = SecretStore::new(&directory);
+
+        // Without a keyring, secrets go into the file.
+        let reference = store.set(ID, "one").unwrap();
+        assert_eq!(reference, format!("file:{}", ID));
+        assert_eq!(store.get(&reference).unwrap(), "one");
+        assert_eq!(mode(&store.file_path()), 0o600);
+        assert_eq!(mode(&store.key_path()), 0o600);
+
+        // Unchanged secrets aren't written again.
+        let contents = std::fs::read(store.file_path()).unwrap();
+        assert_eq!(
+            store.update(Some(&reference), ID, "one").unwrap(),
+            reference
+        );
+        assert_eq!(std::fs::read(store.file_path()).unwrap(), contents);
+    }
+
+    #[test]
+    #[ignore = "a step of stores_secrets_in_keyring_or_file"]
+    fn with_keyring() {
+        let Some(directory) = step_directory() else {
+            return;
+        };
+        let store = SecretStore::new(&directory);
+        let reference = format!("file:{}", ID);
+        assert_eq!(store.get(&reference).unwrap(), "one");
+
+        // With a keyring, they move there.
+        let keyring = serve_keyring(std::env::var("DBUS_SESSION_BUS_ADDRESS").unwrap());
+        let reference = store.update(Some(&reference), ID, "two").unwrap();
+        assert_eq!(reference, format!("keyring:{}", ID));
+        assert!(store.read_file().unwrap().is_empty());
+        assert_eq!(store.get(&reference).unwrap(), "two");
+        {
+            let keyring = keyring.lock().unwrap();
+            assert_eq!(keyring.items.len(), 1);
+            let item = keyring.items.values().next().unwrap();
+            assert_eq!(item.attributes["service"], SERVICE);
+            assert_eq!(item.attributes["username"], ID);
+            assert_eq!(item.secret, b"two");
+        }
+
+        let writes = keyring.lock().unwrap().writes;
+        assert_eq!(
+            store.update(Some(&reference), ID, "two").unwrap(),
+            reference
+        );
+        assert_eq!(keyring.lock().unwrap().writes, writes);
+
+        store.update(Some(&reference), ID, "three").unwrap();
+        assert_eq!(keyring.lock().unwrap().writes, writes + 1);
+        assert_eq!(keyring.lock().unwrap().items.len(), 1);
+        assert_eq!(store.get(&reference).unwrap(), "three");
+
+        store.delete(&reference).unwrap();
+        assert!(keyring.lock().unwrap().items.is_empty());
+        assert!(store.get(&reference).is_err());
+        assert_eq!(store.known(&reference), None);
+    }
+}
@@ -0,0 +1,1098 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::actions::Action;
+use crate::error::{CommandError, ErrorKind};
+
+pub mod bundle;
+mod format;
+mod migrations;
+mod overrides;
+mod store;
+mod validation;
+
+use crate::secrets::SecretStore;
+pub use format::Format;
+use migrations::MigrationError;
+pub use migrations::CURRENT_SCHEMA_VERSION;
+pub use overrides::{EffectiveSettings, Overrides};
+pub use store::{watch, ChangeSource, SettingsChanged, SettingsStore};
+pub use validation::{parse, validate, FieldError};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Define settings
+/// A Home Assistant server to connect to.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HomeAssistantSettings {
+    /// Long-lived access token. The app moves it into the system keyring and
+    /// keeps only `access_token_secret` in the settings file.
+    // Only held in memory and sent to the webview, see `save`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub access_token: String,
+    /// Reference to the access token in the secret store, managed by the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_secret: Option<String>,
+    /// Where Home Assistant is served, including any path prefix added by a
+    /// reverse proxy, e.g. `https://proxy.example/ha/`.
+    pub base_url: Url,
+    /// Talk to Home Assistant over `/api/conversation/process` only, without a
+    /// websocket, for reverse proxies that don't pass on websocket upgrades.
+    /// Pipelines, and with them text to speech, aren't available then.
+    pub rest_only: bool,
+    // Keys this version doesn't know, e.g. written by a newer version or by
+    // hand, kept so saving doesn't drop them.
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for HomeAssistantSettings {
+    fn default() -> Self {
+        HomeAssistantSettings {
+            access_token: "".to_string(),
+            access_token_secret: None,
+            base_url: Url::parse("http://homeassistant.local:8123/").unwrap(),
+            rest_only: false,
+            extra: Map::new(),
+        }
+    }
+}
+
+impl HomeAssistantSettings {
+    // Resolve a path relative to the base URL. Absolute paths as returned by
+    // Home Assistant (e.g. `/api/tts_proxy/...`) keep the base URL's prefix.
+    pub fn url(&self, path: &str) -> Result<Url, String> {
+        let mut base_url = self.base_url.clone();
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        base_url
+            .join(path.trim_start_matches('/'))
+            .map_err(|e| format!("Invalid Home Assistant URL for {}: {}", path, e))
+    }
+
+    pub fn websocket_url(&self) -> Result<Url, String> {
+        let mut url = self.url("api/websocket")?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        // Switching from a special scheme to another special scheme can't fail.
+        url.set_scheme(scheme).unwrap();
+        Ok(url)
+    }
+
+    // The base URL without the trailing slash, which is the form
+    // home-assistant-js-websocket and the frontend expect.
+    pub fn hass_url(&self) -> String {
+        self.base_url.as_str().trim_end_matches('/').to_string()
+    }
+}
+
+// Sent to the webview so it never has to build URLs itself.
+#[derive(Serialize)]
+pub struct HomeAssistantUrls {
+    pub base_url: String,
+    pub websocket_url: String,
+}
+
+impl HomeAssistantUrls {
+    pub fn new(home_assistant: &HomeAssistantSettings) -> Result<Self, String> {
+        Ok(HomeAssistantUrls {
+            base_url: home_assistant.hass_url(),
+            websocket_url: home_assistant.websocket_url()?.to_string(),
+        })
+    }
+}
+
+/// What clicking the tray icon does.
+// Tauri only reports left clicks on Windows and macOS, and doesn't report
+// middle clicks at all, so those aren't offered.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TraySettings {
+    /// Action for a single left click, on Windows and macOS only.
+    pub left_click: Action,
+    /// Action for a double click.
+    pub double_click: Action,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for TraySettings {
+    fn default() -> Self {
+        TraySettings {
+            left_click: Action::None,
+            double_click: Action::ToggleWindow,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// The API spoken by the speech to text server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SttBackend {
+    /// OpenAI's `/v1/audio/transcriptions`, also served by
+    /// faster-whisper-server, LocalAI and others.
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// The `/asr` endpoint of openai-whisper-asr-webservice.
+    WhisperAsr,
+}
+
+/// Where recorded audio is sent to be turned into text.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SttSettings {
+    pub backend: SttBackend,
+    /// Full URL of the transcription endpoint. Voice input is off until it is
+    /// set.
+    pub endpoint: Option<Url>,
+    /// Model to transcribe with, e.g. `whisper-1`. Only sent to the `openai`
+    /// backend.
+    pub model: String,
+    /// Language code such as `en`, detected by the backend when not set.
+    pub language: Option<String>,
+    /// API key sent as a bearer token. Like access tokens, the app moves it
+    /// into the system keyring and keeps only `api_key_secret` in the file.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub api_key: String,
+    /// Reference to the API key in the secret store, managed by the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_secret: Option<String>,
+    /// How long to wait for a transcription.
+    pub timeout_seconds: u64,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for SttSettings {
+    fn default() -> Self {
+        SttSettings {
+            backend: SttBackend::OpenAi,
+            endpoint: None,
+            model: "whisper-1".to_string(),
+            language: None,
+            api_key: String::new(),
+            api_key_secret: None,
+            timeout_seconds: 30,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// Applied to every request the app makes itself, i.e. not by the webview.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct NetworkSettings {
+    /// e.g. `http://proxy.example:3128`. When not set, the HTTPS_PROXY and
+    /// NO_PROXY environment variables are used.
+    pub proxy: Option<Url>,
+    /// Hosts to reach without the proxy, e.g. `localhost` or `.example.com`.
+    pub no_proxy: Vec<String>,
+    /// PEM files with extra CA certificates to trust, e.g. a private CA.
+    pub ca_certificates: Vec<PathBuf>,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+/// A window position and size in physical pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where the window was last put on each monitor. Kept up to date by the app
+/// as the window is moved, so changes made here are ignored while it runs.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct WindowState {
+    /// Key of the monitor the window was last on.
+    pub last_monitor: Option<String>,
+    /// By monitor name and resolution, e.g. `DELL U2720Q@3840x2160`.
+    pub monitors: BTreeMap<String, WindowGeometry>,
+}
+
+/// Settings of Home Assistant Assist Desktop.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Settings {
+    /// Version of the settings format. Older files are upgraded by the app.
+    // Missing when the settings come from the webview; `update_settings` fills it in.
+    #[serde(default)]
+    pub schema_version: u32,
+    /// Start the app when logging in.
+    pub autostart: bool,
+    /// Name of the entry in `profiles` the app connects to.
+    pub active_profile: String,
+    /// Home Assistant servers by profile name.
+    pub profiles: BTreeMap<String, HomeAssistantSettings>,
+    pub tray: TraySettings,
+    /// Text sent to Assist by name, for the `quick_command` action.
+    pub quick_commands: BTreeMap<String, String>,
+    /// Global shortcuts, from accelerator (e.g. `Ctrl+Alt+A` or `F9`) to
+    /// action.
+    pub shortcuts: BTreeMap<String, Action>,
+    /// Speech to text for voice input.
+    pub stt: SttSettings,
+    pub network: NetworkSettings,
+    pub window_state: WindowState,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            autostart: false,
+            active_profile: DEFAULT_PROFILE.to_string(),
+            profiles: BTreeMap::from([(
+                DEFAULT_PROFILE.to_string(),
+                HomeAssistantSettings::default(),
+            )]),
+            tray: TraySettings::default(),
+            quick_commands: BTreeMap::new(),
+            shortcuts: default_shortcuts(),
+            stt: SttSettings::default(),
+            network: NetworkSettings::default(),
+            window_state: WindowState::default(),
+            extra: Map::new(),
+        }
+    }
+}
+
+fn default_shortcuts() -> BTreeMap<String, Action> {
+    BTreeMap::from([
+        ("Ctrl+Alt+A".to_string(), Action::ToggleWindow),
+        ("Ctrl+Shift+A".to_string(), Action::TriggerVoicePipeline),
+    ])
+}
+
+impl Settings {
+    // The Home Assistant server of the active profile.
+    pub fn home_assistant(&self) -> Result<&HomeAssistantSettings, String> {
+        self.profiles
+            .get(&self.active_profile)
+            .ok_or_else(|| format!("Active profile \"{}\" does not exist", self.active_profile))
+    }
+
+    pub fn add_profile(
+        &mut self,
+        name: &str,
+        home_assistant: HomeAssistantSettings,
+    ) -> Result<(), String> {
+        if self.profiles.contains_key(name) {
+            return Err(format!("Profile \"{}\" already exists", name));
+        }
+        self.profiles.insert(name.to_string(), home_assistant);
+        Ok(())
+    }
+
+    pub fn remove_profile(&mut self, name: &str) -> Result<HomeAssistantSettings, String> {
+        if name == self.active_profile {
+            return Err(format!(
+                "Profile \"{}\" is active, activate another profile before removing it",
+                name
+            ));
+        }
+        self.profiles
+            .remove(name)
+            .ok_or_else(|| format!("Profile \"{}\" does not exist", name))
+    }
+
+    pub fn activate_profile(&mut self, name: &str) -> Result<(), String> {
+        if !self.profiles.contains_key(name) {
+            return Err(format!("Profile \"{}\" does not exist", name));
+        }
+        self.active_profile = name.to_string();
+        Ok(())
+    }
+}
+
+pub fn settings_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CommandError> {
+    let directory = crate::paths::config_dir(app_handle)
+        .ok_or_else(|| CommandError::new(ErrorKind::Io, "Could not find the config directory"))?;
+    Ok(find_settings_file(&directory))
+}
+
+// The settings file in `directory`: settings.toml, settings.yaml,
+// settings.yml or settings.json, in that order if there are several. Without
+// any, new settings are written as JSON.
+pub fn find_settings_file(directory: &Path) -> PathBuf {
+    let existing: Vec<PathBuf> = Format::ALL
+        .iter()
+        .flat_map(|format| format.file_names())
+        .map(|name| directory.join(name))
+        .filter(|path| path.exists())
+        .collect();
+
+    if let [used, ignored @ ..] = existing.as_slice() {
+        for path in ignored {
+            log::warn!(
+                "Ignoring {} as {} takes precedence",
+                path.display(),
+                used.display()
+            );
+        }
+    }
+
+    existing
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| directory.join(Format::Json.file_name()))
+}
+
+// Whether `path` is a settings file in any of the formats.
+pub fn is_settings_file(path: &Path) -> bool {
+    Format::ALL
+        .iter()
+        .flat_map(|format| format.file_names())
+        .any(|name| path.file_name() == Some(name.as_ref()))
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoverySource {
+    Backup,
+    Defaults,
+}
+
+// Sent to the webview as the `settings-recovered` event when settings.json
+// could not be read and had to be replaced.
+#[derive(Clone, Serialize)]
+pub struct Recovery {
+    pub source: RecoverySource,
+    pub reason: String,
+    pub message: String,
+}
+
+enum ReadError {
+    // The file could not be read at all, e.g. missing permissions.
+    Io(String),
+    // The file was read but is not valid settings, e.g. after a crash mid-write.
+    Corrupt(String),
+    // The file was written by a newer version of the app.
+    Unsupported(String),
+}
+
+impl From<ReadError> for String {
+    fn from(error: ReadError) -> Self {
+        match error {
+            ReadError::Io(message)
+            | ReadError::Corrupt(message)
+            | ReadError::Unsupported(message) => message,
+        }
+    }
+}
+
+// JSON Schema of settings.json, for validating it in editors and config
+// management tools.
+pub fn schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Settings)
+}
+
+// e.g. settings.toml.bak for settings.toml.
+fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".corrupt")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Secrets are kept next to the settings file they belong to.
+fn secret_store(path: &Path) -> SecretStore {
+    SecretStore::new(path.parent().unwrap_or(Path::new(".")))
+}
+
+fn access_token_secret_id(profile: &str) -> String {
+    format!("profiles/{}/access_token", profile)
+}
+
+const STT_API_KEY_SECRET_ID: &str = "stt/api_key";
+
+// Load settings from disk, creating the file with defaults if it doesn't exist
+// and upgrading it to the current schema version if it is older. If the file
+// is corrupt, it is replaced with the last good backup (or the defaults) and
+// the returned `Recovery` describes what happened. Access tokens are read
+// from the secret store.
+pub fn load(path: &Path) -> Result<(Settings, Option<Recovery>), String> {
+    let (mut settings, recovery) = load_file(path)?;
+    load_secrets(path, &mut settings)?;
+
+    Ok((settings, recovery))
+}
+
+// Read settings that were changed outside of the app. Unlike `load` this never
+// touches a file it can't read, as it may be half way through being edited.
+pub fn reload(path: &Path) -> Result<Settings, String> {
+    let (mut settings, _) = read(path)?;
+    load_secrets(path, &mut settings)?;
+
+    Ok(settings)
+}
+
+fn load_secrets(path: &Path, settings: &mut Settings) -> Result<(), String> {
+    let store = secret_store(path);
+    let mut plaintext_tokens = false;
+    for (name, home_assistant) in settings.profiles.iter_mut() {
+        match &home_assistant.access_token_secret {
+            Some(reference) => match store.get(reference) {
+                Ok(access_token) => home_assistant.access_token = access_token,
+                Err(e) => log::error!("Could not read access token for profile {}: {}", name, e),
+            },
+            None => plaintext_tokens |= !home_assistant.access_token.is_empty(),
+        }
+    }
+
+    // The speech to text API key was always stored as a secret.
+    if let Some(reference) = &settings.stt.api_key_secret {
+        match store.get(reference) {
+            Ok(api_key) => settings.stt.api_key = api_key,
+            Err(e) => log::error!("Could not read speech to text API key: {}", e),
+        }
+    }
+
+    // Settings written by older versions have the token in plaintext. Move it
+    // into the secret store, and overwrite the backup as well so no copy of
+    // the token is left behind.
+    if plaintext_tokens {
+        log::info!("Moving access tokens into the secret store");
+        let contents = serialize(path, settings)?;
+        write_atomically(path, &contents)?;
+        write_atomically(&backup_path(path), &contents)?;
+    }
+
+    Ok(())
+}
+
+fn load_file(path: &Path) -> Result<(Settings, Option<Recovery>), String> {
+    // If the directory doesn't exist, create it.
+    if let Some(parent) = path.parent() {
+        if !parent.exists() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+    }
+
+    // Check if the file exists.
+    if !path.exists() {
+        save(path, &Settings::default())?;
+    }
+
+    let reason = match read(path) {
+        Ok((settings, migrated)) => {
+            // Persist the upgraded file so the migration only runs once.
+            if migrated {
+                log::info!(
+                    "Migrated settings to schema version {}",
+                    settings.schema_version
+                );
+                save(path, &settings)?;
+            }
+            return Ok((settings, None));
+        }
+        Err(ReadError::Corrupt(reason)) => reason,
+        Err(error) => return Err(error.into()),
+    };
+
+    log::error!("Settings at {} are corrupt: {}", path.display(), reason);
+
+    // Keep the corrupt file around for inspection.
+    let corrupt = corrupt_path(path);
+    if let Err(e) = std::fs::rename(path, &corrupt) {
+        log::warn!(
+            "Could not move corrupt settings to {}: {}",
+            corrupt.display(),
+            e
+        );
+    }
+
+    let (settings, source) = match read(&backup_path(path)) {
+        Ok((settings, _)) => {
+            log::warn!("Restoring settings from backup");
+            (settings, RecoverySource::Backup)
+        }
+        Err(error) => {
+            log::warn!(
+                "Could not read settings backup ({}), restoring defaults",
+                String::from(error)
+            );
+            (Settings::default(), RecoverySource::Defaults)
+        }
+    };
+
+    save(path, &settings)?;
+
+    let message = match source {
+        RecoverySource::Backup => {
+            "Your settings file was damaged and has been restored from a backup."
+        }
+        RecoverySource::Defaults => {
+            "Your settings file was damaged and has been reset to the defaults."
+        }
+    };
+
+    Ok((
+        settings,
+        Some(Recovery {
+            source,
+            reason,
+            message: message.to_string(),
+        }),
+    ))
+}
+
+// Read and migrate a settings file without writing anything back.
+fn read(path: &Path) -> Result<(Settings, bool), ReadError> {
+    // Read the file as untyped JSON so older schemas can be migrated before
+    // deserializing.
+    let contents = std::fs::read(path).map_err(|e| ReadError::Io(e.to_string()))?;
+    let mut value = Format::from_path(path)
+        .parse(&contents)
+        .map_err(ReadError::Corrupt)?;
+
+    let migrated = migrations::migrate(&mut value).map_err(|e| match e {
+        MigrationError::Invalid(_) => ReadError::Corrupt(e.to_string()),
+        MigrationError::Unsupported(_) => ReadError::Unsupported(e.to_string()),
+    })?;
+
+    let settings: Settings =
+        serde_json::from_value(value).map_err(|e| ReadError::Corrupt(e.to_string()))?;
+
+    Ok((settings, migrated))
+}
+
+// Write settings without ever leaving a partially written settings file
+// behind. The previous file is kept as e.g. settings.json.bak as long as it
+// was readable, so a bad write can always be rolled back to the last good
+// state. The file keeps its format. Returns the settings as they read back,
+// i.e. with references to their secrets updated.
+pub fn save(path: &Path, settings: &Settings) -> Result<Settings, String> {
+    write_settings(path, settings, true).map(|(settings, _)| settings)
+}
+
+// `save`, optionally without keeping the previous file as the backup, e.g.
+// when only state the app keeps itself changed. Also returns what was written.
+fn write_settings(
+    path: &Path,
+    settings: &Settings,
+    backup: bool,
+) -> Result<(Settings, Vec<u8>), String> {
+    let mut settings = settings.clone();
+    let contents = serialize(path, &mut settings)?;
+
+    if backup {
+        if let Ok(previous) = std::fs::read(path) {
+            if Format::from_path(path).parse(&previous).is_ok() {
+                write_atomically(&backup_path(path), &previous)?;
+            }
+        }
+    }
+
+    write_atomically(path, &contents)?;
+    Ok((settings, contents))
+}
+
+// Serialize settings for writing to `path`, moving access tokens into the
+// secret store so only references to them end up on disk. The references in
+// `settings` are updated to match.
+fn serialize(path: &Path, settings: &mut Settings) -> Result<Vec<u8>, String> {
+    let store = secret_store(path);
+
+    // Only secrets that changed are written, so the keyring isn't asked on
+    // every save.
+    for (name, home_assistant) in settings.profiles.iter_mut() {
+        if !home_assistant.access_token.is_empty() {
+            let reference = store.update(
+                home_assistant.access_token_secret.as_deref(),
+                &access_token_secret_id(name),
+                &home_assistant.access_token,
+            )?;
+            home_assistant.access_token_secret = Some(reference);
+        } else if let Some(reference) = home_assistant.access_token_secret.clone() {
+            // Like the speech to text API key below: the token was cleared on
+            // purpose if it was read, otherwise the keyring may have been
+            // unavailable at load and the stored copy is kept.
+            if store.known(&reference).is_some() {
+                if let Err(e) = store.delete(&reference) {
+                    log::warn!("Could not delete access token for profile {}: {}", name, e);
+                }
+                home_assistant.access_token_secret = None;
+            }
+        }
+    }
+
+    if !settings.stt.api_key.is_empty() {
+        let reference = store.update(
+            settings.stt.api_key_secret.as_deref(),
+            STT_API_KEY_SECRET_ID,
+            &settings.stt.api_key,
+        )?;
+        settings.stt.api_key_secret = Some(reference);
+    } else if let Some(reference) = settings.stt.api_key_secret.clone() {
+        // The key was removed in settings, so remove the stored copy too. An
+        // empty key that was never read may just mean the keyring was
+        // unavailable at load, so then the stored copy is kept.
+        if store.known(&reference).is_some() {
+            if let Err(e) = store.delete(&reference) {
+                log::warn!("Could not delete speech to text API key: {}", e);
+            }
+            settings.stt.api_key_secret = None;
+        }
+    }
+
+    let mut written = settings.clone();
+    for home_assistant in written.profiles.values_mut() {
+        home_assistant.access_token.clear();
+    }
+    written.stt.api_key.clear();
+
+    // Serialize the Settings struct in the format of the file.
+    let value = serde_json::to_value(&written).map_err(|e| e.to_string())?;
+    Format::from_path(path).write(&value)
+}
+
+// Rewrite the settings file at `path` in another format and remove the old
+// file, so the new one is used from now on. Works on the file as written
+// rather than on `Settings`, so keys this version doesn't know are kept.
+// Returns the path of the new file.
+pub fn convert(path: &Path, format: Format) -> Result<PathBuf, String> {
+    let target = path.with_file_name(format.file_name());
+    if target == path {
+        return Ok(target);
+    }
+    if target.exists() {
+        return Err(format!(
+            "{} already exists, remove it before converting",
+            target.display()
+        ));
+    }
+
+    let contents =
+        std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let value = Format::from_path(path)
+        .parse(&contents)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    write_atomically(&target, &format.write(&value)?)?;
+
+    // The backup of the old file is left behind, in case anything was lost.
+    std::fs::remove_file(path)
+        .map_err(|e| format!("Could not remove {}: {}", path.display(), e))?;
+
+    log::info!("Converted {} to {}", path.display(), target.display());
+    Ok(target)
+}
+
+// Remove the secrets of a profile that is no longer in the settings.
+pub fn delete_secrets(path: &Path, home_assistant: &HomeAssistantSettings) -> Result<(), String> {
+    match &home_assistant.access_token_secret {
+        Some(reference) => secret_store(path).delete(reference),
+        None => Ok(()),
+    }
+}
+
+// Write to a temporary file next to `path`, flush it to disk and rename it
+// into place. Renames within a directory are atomic, so readers see either
+// the old or the new contents, never a mix.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    write_atomically_with(path, contents, false)
+}
+
+// Like `write_atomically`, but for files holding secrets, which are only
+// readable and writable by the current user on Unix.
+pub(crate) fn write_atomically_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    write_atomically_with(path, contents, true)
+}
+
+fn write_atomically_with(path: &Path, contents: &[u8], private: bool) -> Result<(), String> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        // The mode only applies to new files, so don't reuse a leftover one.
+        match std::fs::remove_file(&temporary_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+    }
+    let mut file: File = options.open(&temporary_path).map_err(|e| e.to_string())?;
+    file.write_all(contents).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    drop(file);
+
+    std::fs::rename(&temporary_path, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_fields_in_the_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let description = |definition: &str, field: &str| {
+            schema["definitions"][definition]["properties"][field]["description"].clone()
+        };
+        assert!(schema["properties"]["active_profile"]["description"].is_string());
+        assert!(description("HomeAssistantSettings", "base_url").is_string());
+        assert!(description("SttSettings", "endpoint").is_string());
+        assert!(description("NetworkSettings", "proxy").is_string());
+    }
+
+    // Properties of each interface in the webview's settings types, and
+    // whether they are optional.
+    fn typescript_interfaces(source: &str) -> BTreeMap<String, BTreeMap<String, bool>> {
+        let mut interfaces = BTreeMap::new();
+        let mut current: Option<(String, BTreeMap<String, bool>)> = None;
+        for line in source.lines().map(str::trim) {
+            if let Some(name) = line
+                .strip_prefix("export interface ")
+                .and_then(|line| line.strip_suffix(" {"))
+            {
+                current = Some((name.to_string(), BTreeMap::new()));
+            } else if line == "}" {
+                if let Some((name, properties)) = current.take() {
+                    interfaces.insert(name, properties);
+                }
+            } else if let (Some((_, properties)), Some((property, _))) =
+                (&mut current, line.split_once(':'))
+            {
+                if !line.starts_with("//") {
+                    let optional = property.ends_with('?');
+                    properties.insert(property.trim_end_matches('?').to_string(), optional);
+                }
+            }
+        }
+        interfaces
+    }
+
+    #[test]
+    fn matches_the_typescript_types() {
+        use std::collections::BTreeSet;
+
+        let interfaces = typescript_interfaces(include_str!("../../src/types/settings.ts"));
+        let schema = serde_json::to_value(schema()).unwrap();
+        let definitions = schema["definitions"].as_object().unwrap();
+
+        let mut checked = Vec::new();
+        for (name, definition) in std::iter::once(("Settings", &schema)).chain(
+            definitions
+                .iter()
+                .map(|(name, definition)| (name.as_str(), definition)),
+        ) {
+            // Enums have no properties and are checked by hand.
+            let Some(properties) = definition["properties"].as_object() else {
+                continue;
+            };
+            let typescript = interfaces
+                .get(name)
+                .unwrap_or_else(|| panic!("src/types/settings.ts has no {}", name));
+            assert_eq!(
+                typescript.keys().collect::<BTreeSet<_>>(),
+                properties.keys().collect::<BTreeSet<_>>(),
+                "properties of {}",
+                name
+            );
+            for required in definition["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap();
+                assert!(
+                    !typescript[required],
+                    "{}.{} is required in Rust but optional in TypeScript",
+                    name, required
+                );
+            }
+            checked.push(name);
+        }
+        assert!(checked.contains(&"Settings"));
+        assert!(checked.contains(&"HomeAssistantSettings"));
+        assert!(checked.contains(&"WindowGeometry"));
+    }
+
+    #[test]
+    fn finds_settings_files_in_order_of_precedence() {
+        let directory = tempfile::tempdir().unwrap();
+        let directory = directory.path();
+        assert_eq!(
+            find_settings_file(directory),
+            directory.join("settings.json")
+        );
+
+        for name in [
+            "settings.json",
+            "settings.yml",
+            "settings.yaml",
+            "settings.toml",
+        ] {
+            std::fs::write(directory.join(name), "").unwrap();
+            assert_eq!(find_settings_file(directory), directory.join(name));
+            assert!(is_settings_file(&directory.join(name)));
+        }
+    }
+
+    fn profile(base_url: &str) -> HomeAssistantSettings {
+        HomeAssistantSettings {
+            base_url: Url::parse(base_url).unwrap(),
+            ..HomeAssistantSettings::default()
+        }
+    }
+
+    #[test]
+    fn adds_and_activates_profiles() {
+        let mut settings = Settings::default();
+        settings
+            .add_profile("work", profile("https://work.example/"))
+            .unwrap();
+        assert_eq!(settings.active_profile, DEFAULT_PROFILE);
+
+        settings.activate_profile("work").unwrap();
+        assert_eq!(
+            settings.home_assistant().unwrap().base_url.as_str(),
+            "https://work.example/"
+        );
+        assert!(validate(&settings).is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicate_profiles() {
+        let mut settings = Settings::default();
+        let error = settings
+            .add_profile(DEFAULT_PROFILE, profile("https://other.example/"))
+            .unwrap_err();
+        assert!(error.contains("already exists"));
+        assert_eq!(
+            settings.profiles[DEFAULT_PROFILE].base_url,
+            HomeAssistantSettings::default().base_url
+        );
+    }
+
+    #[test]
+    fn removes_only_inactive_profiles() {
+        let mut settings = Settings::default();
+        settings
+            .add_profile("work", profile("https://work.example/"))
+            .unwrap();
+
+        let error = settings.remove_profile(DEFAULT_PROFILE).unwrap_err();
+        assert!(error.contains("is active"));
+        assert!(settings.profiles.contains_key(DEFAULT_PROFILE));
+
+        let removed = settings.remove_profile("work").unwrap();
+        assert_eq!(removed.base_url.as_str(), "https://work.example/");
+        assert!(settings.remove_profile("work").is_err());
+        assert_eq!(settings.profiles.len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_profiles_from_the_command_line() {
+        // As passed to a second instance, e.g. `--profile work`.
+        let args: Vec<String> = ["app", "--profile", "work"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let name = crate::cli::value(&args, "--profile").unwrap();
+
+        let mut settings = Settings::default();
+        let error = settings.activate_profile(&name).unwrap_err();
+        assert_eq!(error, "Profile \"work\" does not exist");
+        assert_eq!(settings.active_profile, DEFAULT_PROFILE);
+    }
+
+    #[test]
+    fn keeps_the_previous_file_as_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        save(&path, &settings).unwrap();
+        let first = std::fs::read(&path).unwrap();
+        assert!(!backup_path(&path).exists());
+
+        settings.autostart = true;
+        save(&path, &settings).unwrap();
+        assert_eq!(std::fs::read(backup_path(&path)).unwrap(), first);
+        assert!(reload(&path).unwrap().autostart);
+        assert!(!with_suffix(&path, ".tmp").exists());
+
+        // A file that can't be read is not worth keeping as the backup.
+        std::fs::write(&path, "{").unwrap();
+        save(&path, &settings).unwrap();
+        assert_eq!(std::fs::read(backup_path(&path)).unwrap(), first);
+    }
+
+    #[test]
+    fn restores_truncated_settings_from_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        settings.autostart = true;
+        save(&path, &settings).unwrap();
+        settings.autostart = false;
+        save(&path, &settings).unwrap();
+
+        // As if the app crashed half way through writing the file.
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+
+        let (settings, recovery) = load(&path).unwrap();
+        assert!(settings.autostart);
+        let recovery = serde_json::to_value(recovery.unwrap()).unwrap();
+        assert_eq!(recovery["source"], "backup");
+        assert!(recovery["reason"]
+            .as_str()
+            .is_some_and(|reason| !reason.is_empty()));
+        assert!(recovery["message"]
+            .as_str()
+            .unwrap()
+            .contains("restored from a backup"));
+
+        assert_eq!(
+            std::fs::read(corrupt_path(&path)).unwrap(),
+            &contents[..contents.len() / 2]
+        );
+        assert!(reload(&path).unwrap().autostart);
+        assert!(load(&path).unwrap().1.is_none());
+    }
+
+    #[test]
+    fn restores_defaults_without_a_good_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        std::fs::write(&path, r#"{ "autostart": tr"#).unwrap();
+        std::fs::write(backup_path(&path), "not json").unwrap();
+
+        let (settings, recovery) = load(&path).unwrap();
+        assert!(!settings.autostart);
+        assert_eq!(settings.active_profile, DEFAULT_PROFILE);
+        let recovery = serde_json::to_value(recovery.unwrap()).unwrap();
+        assert_eq!(recovery["source"], "defaults");
+        assert!(recovery["message"]
+            .as_str()
+            .unwrap()
+            .contains("reset to the defaults"));
+        assert!(reload(&path).is_ok());
+    }
+
+    #[test]
+    fn keeps_access_token_that_could_not_be_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        // The secrets file doesn't exist, as if the keyring were unavailable.
+        settings
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .access_token_secret = Some("file:profiles/default/access_token".to_string());
+        std::fs::write(&path, serde_json::to_vec(&settings).unwrap()).unwrap();
+
+        let (settings, _) = load(&path).unwrap();
+        assert_eq!(settings.profiles[DEFAULT_PROFILE].access_token, "");
+        save(&path, &settings).unwrap();
+
+        let settings = reload(&path).unwrap();
+        assert_eq!(
+            settings.profiles[DEFAULT_PROFILE]
+                .access_token_secret
+                .as_deref(),
+            Some("file:profiles/default/access_token")
+        );
+    }
+
+    #[test]
+    fn removes_access_token_that_was_cleared() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        let reference = secret_store(&path)
+            .write_to_file("profiles/default/access_token", "token")
+            .unwrap();
+        settings
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .access_token_secret = Some(reference.clone());
+        std::fs::write(&path, serde_json::to_vec(&settings).unwrap()).unwrap();
+
+        let (mut settings, _) = load(&path).unwrap();
+        assert_eq!(settings.profiles[DEFAULT_PROFILE].access_token, "token");
+        settings
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .access_token
+            .clear();
+        save(&path, &settings).unwrap();
+
+        let settings = reload(&path).unwrap();
+        let home_assistant = &settings.profiles[DEFAULT_PROFILE];
+        assert_eq!(home_assistant.access_token_secret, None);
+        assert_eq!(home_assistant.access_token, "");
+        assert!(secret_store(&path).get(&reference).is_err());
+    }
+
+    #[test]
+    fn keeps_api_key_that_could_not_be_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        // The secrets file doesn't exist, as if the keyring were unavailable.
+        settings.stt.api_key_secret = Some("file:stt/api_key".to_string());
+        std::fs::write(&path, serde_json::to_vec(&settings).unwrap()).unwrap();
+
+        let (settings, _) = load(&path).unwrap();
+        assert_eq!(settings.stt.api_key, "");
+        save(&path, &settings).unwrap();
+
+        let settings = reload(&path).unwrap();
+        assert_eq!(
+            settings.stt.api_key_secret.as_deref(),
+            Some("file:stt/api_key")
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_keys() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.toml");
+        let mut value = serde_json::to_value(Settings::default()).unwrap();
+        value["theme"] = "dark".into();
+        value["profiles"]["default"]["verify_ssl"] = false.into();
+        value["stt"]["prompt"] = "Home Assistant".into();
+        std::fs::write(&path, Format::Toml.write(&value).unwrap()).unwrap();
+
+        let (mut settings, _) = load(&path).unwrap();
+        settings.autostart = true;
+        save(&path, &settings).unwrap();
+
+        let value = Format::Toml.parse(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(value["autostart"], true);
+        assert_eq!(value["theme"], "dark");
+        assert_eq!(value["profiles"]["default"]["verify_ssl"], false);
+        assert_eq!(value["stt"]["prompt"], "Home Assistant");
+        // Unknown keys don't end up in the schema as settings.
+        let schema = serde_json::to_value(schema()).unwrap();
+        assert!(schema["properties"].get("extra").is_none());
+    }
+}
@@ -0,0 +1,356 @@
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::Key;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use super::{migrations, Settings};
+use crate::secrets;
+
+const BUNDLE_FORMAT: &str = "home-assistant-assist-settings";
+const BUNDLE_VERSION: u32 = 1;
+
+// A portable copy of the settings for setting up other machines. Secret store
+// references only make sense on the machine that wrote them, so access tokens
+// are either left out or encrypted with a passphrase.
+#[derive(Serialize, Deserialize)]
+pub struct Bundle {
+    format: String,
+    version: u32,
+    // Kept untyped so bundles exported by older versions can be migrated
+    // like settings.json.
+    settings: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access_tokens: Option<EncryptedTokens>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedTokens {
+    salt: String,
+    // Encrypted access token by profile name.
+    profiles: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stt_api_key: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    // Dotted paths of the settings that differ after the import.
+    pub changed: Vec<String>,
+    // False for a dry run.
+    pub applied: bool,
+}
+
+pub fn export(settings: &Settings, passphrase: Option<&str>) -> Result<Bundle, String> {
+    let mut settings = settings.clone();
+
+    let access_tokens = match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let key = derive_key(passphrase, &salt)?;
+
+            let mut profiles = BTreeMap::new();
+            for (name, home_assistant) in &settings.profiles {
+                if !home_assistant.access_token.is_empty() {
+                    profiles.insert(
+                        name.clone(),
+                        secrets::encrypt(&key, &home_assistant.access_token)?,
+                    );
+                }
+            }
+
+            let stt_api_key = match settings.stt.api_key.as_str() {
+                "" => None,
+                api_key => Some(secrets::encrypt(&key, api_key)?),
+            };
+
+            Some(EncryptedTokens {
+                salt: BASE64.encode(salt),
+                profiles,
+                stt_api_key,
+            })
+        }
+        None => None,
+    };
+
+    for home_assistant in settings.profiles.values_mut() {
+        home_assistant.access_token.clear();
+        home_assistant.access_token_secret = None;
+    }
+    settings.stt.api_key.clear();
+    settings.stt.api_key_secret = None;
+    // Window positions only make sense with this machine's monitors.
+    settings.window_state = Default::default();
+
+    Ok(Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        settings: serde_json::to_value(&settings).map_err(|e| e.to_string())?,
+        access_tokens,
+    })
+}
+
+// Merge a bundle into the current settings. Profiles and quick commands from
+// the bundle are added or replaced by name and other ones are kept. A profile without a
+// token in the bundle keeps the token it already has on this machine. Other
+// settings, including the proxy and CA certificates, are replaced.
+pub fn import(
+    current: &Settings,
+    bundle: Bundle,
+    passphrase: Option<&str>,
+) -> Result<(Settings, Vec<String>), String> {
+    if bundle.format != BUNDLE_FORMAT {
+        return Err("Not a settings bundle".to_string());
+    }
+    if bundle.version > BUNDLE_VERSION {
+        return Err(format!(
+            "Settings bundle version {} is not supported, please update the app",
+            bundle.version
+        ));
+    }
+
+    let mut value = bundle.settings;
+    migrations::migrate(&mut value).map_err(|e| e.to_string())?;
+    let mut imported: Settings = serde_json::from_value(value).map_err(|e| e.to_string())?;
+
+    if let Some(access_tokens) = &bundle.access_tokens {
+        let passphrase = passphrase.ok_or_else(|| {
+            "This bundle has encrypted access tokens, a passphrase is required".to_string()
+        })?;
+        let salt = BASE64
+            .decode(&access_tokens.salt)
+            .map_err(|e| e.to_string())?;
+        let key = derive_key(passphrase, &salt)?;
+
+        let decrypt = |encrypted: &String| {
+            secrets::decrypt(&key, encrypted)
+                .map_err(|_| "Could not decrypt access tokens, wrong passphrase?".to_string())
+        };
+        for (name, encrypted) in &access_tokens.profiles {
+            if let Some(home_assistant) = imported.profiles.get_mut(name) {
+                home_assistant.access_token = decrypt(encrypted)?;
+            }
+        }
+        if let Some(encrypted) = &access_tokens.stt_api_key {
+            imported.stt.api_key = decrypt(encrypted)?;
+        }
+    }
+
+    let mut merged = current.clone();
+    merged.autostart = imported.autostart;
+    merged.active_profile = imported.active_profile;
+    merged.tray = imported.tray;
+    merged.shortcuts = imported.shortcuts;
+    if imported.stt.api_key.is_empty() {
+        imported.stt.api_key = current.stt.api_key.clone();
+        imported.stt.api_key_secret = current.stt.api_key_secret.clone();
+    }
+    merged.stt = imported.stt;
+    merged.network = imported.network;
+    merged.quick_commands.extend(imported.quick_commands);
+    for (name, mut home_assistant) in imported.profiles {
+        match merged.profiles.get(&name) {
+            Some(existing) if home_assistant.access_token.is_empty() => {
+                home_assistant.access_token = existing.access_token.clone();
+                home_assistant.access_token_secret = existing.access_token_secret.clone();
+            }
+            _ => home_assistant.access_token_secret = None,
+        }
+        merged.profiles.insert(name, home_assistant);
+    }
+
+    let changed = changed_keys(current, &merged)?;
+
+    Ok((merged, changed))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, String> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+// Dotted paths of every value that differs between two sets of settings.
+// Secret store references are machine specific and not reported.
+fn changed_keys(before: &Settings, after: &Settings) -> Result<Vec<String>, String> {
+    let mut before = serde_json::to_value(before).map_err(|e| e.to_string())?;
+    let mut after = serde_json::to_value(after).map_err(|e| e.to_string())?;
+    for value in [&mut before, &mut after] {
+        if let Some(profiles) = value.get_mut("profiles").and_then(Value::as_object_mut) {
+            for profile in profiles.values_mut().filter_map(Value::as_object_mut) {
+                profile.remove("access_token_secret");
+            }
+        }
+        if let Some(stt) = value.get_mut("stt").and_then(Value::as_object_mut) {
+            stt.remove("api_key_secret");
+        }
+    }
+
+    let mut changed = Vec::new();
+    diff("", &before, &after, &mut changed);
+    Ok(changed)
+}
+
+fn diff(path: &str, before: &Value, after: &Value, changed: &mut Vec<String>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let keys: std::collections::BTreeSet<&String> =
+                before.keys().chain(after.keys()).collect();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match (before.get(key), after.get(key)) {
+                    (Some(before), Some(after)) => diff(&path, before, after, changed),
+                    _ => changed.push(path),
+                }
+            }
+        }
+        _ if before != after => changed.push(path.to_string()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::HomeAssistantSettings;
+    use serde_json::json;
+
+    fn changed(before: Value, after: Value) -> Vec<String> {
+        let mut changed = Vec::new();
+        diff("", &before, &after, &mut changed);
+        changed
+    }
+
+    #[test]
+    fn diffs_nothing() {
+        let value = json!({ "a": { "b": [1, 2] }, "c": null });
+        assert!(changed(value.clone(), value).is_empty());
+    }
+
+    #[test]
+    fn diffs_nested_values() {
+        assert_eq!(
+            changed(
+                json!({ "a": { "b": 1, "c": 2 }, "d": true }),
+                json!({ "a": { "b": 1, "c": 3 }, "d": false }),
+            ),
+            ["a.c", "d"]
+        );
+    }
+
+    #[test]
+    fn diffs_added_and_removed_keys() {
+        assert_eq!(
+            changed(
+                json!({ "profiles": { "old": { "x": 1 } } }),
+                json!({ "profiles": { "new": { "x": 1 } } }),
+            ),
+            ["profiles.new", "profiles.old"]
+        );
+    }
+
+    #[test]
+    fn diffs_arrays_and_types_as_a_whole() {
+        assert_eq!(
+            changed(
+                json!({ "a": [1, 2], "b": { "c": 1 } }),
+                json!({ "a": [2, 1], "b": "c" }),
+            ),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn ignores_secret_references() {
+        let before = Settings::default();
+        let mut after = before.clone();
+        after
+            .profiles
+            .get_mut("default")
+            .unwrap()
+            .access_token_secret = Some("keyring:profiles/default/access_token".to_string());
+        after.stt.api_key_secret = Some("keyring:stt/api_key".to_string());
+        assert!(changed_keys(&before, &after).unwrap().is_empty());
+
+        after.stt.api_key = "key".to_string();
+        assert_eq!(changed_keys(&before, &after).unwrap(), ["stt.api_key"]);
+    }
+
+    #[test]
+    fn round_trips_tokens_with_a_passphrase() {
+        let mut settings = Settings::default();
+        let home_assistant = settings.profiles.get_mut("default").unwrap();
+        home_assistant.access_token = "token".to_string();
+        home_assistant.access_token_secret =
+            Some("keyring:profiles/default/access_token".to_string());
+        settings.stt.api_key = "key".to_string();
+
+        let bundle = export(&settings, Some("passphrase")).unwrap();
+        let bundle: Bundle =
+            serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+        assert!(!bundle.settings.to_string().contains("token"));
+
+        let (imported, changed) = import(&Settings::default(), bundle, Some("passphrase")).unwrap();
+        let home_assistant = &imported.profiles["default"];
+        assert_eq!(home_assistant.access_token, "token");
+        assert_eq!(home_assistant.access_token_secret, None);
+        assert_eq!(imported.stt.api_key, "key");
+        assert_eq!(changed, ["profiles.default.access_token", "stt.api_key"]);
+    }
+
+    #[test]
+    fn keeps_tokens_not_in_the_bundle() {
+        let mut current = Settings::default();
+        current.profiles.get_mut("default").unwrap().access_token = "token".to_string();
+
+        let mut exported = Settings::default();
+        exported
+            .add_profile("work", HomeAssistantSettings::default())
+            .unwrap();
+        let bundle = export(&exported, None).unwrap();
+        assert!(import(&current, export(&exported, Some("x")).unwrap(), None).is_err());
+
+        let (imported, changed) = import(&current, bundle, None).unwrap();
+        assert_eq!(imported.profiles["default"].access_token, "token");
+        assert_eq!(changed, ["profiles.work"]);
+    }
+
+    #[test]
+    fn imports_network_settings() {
+        let mut exported = Settings::default();
+        exported.network.proxy = Some("http://proxy.example:3128".parse().unwrap());
+        exported.network.no_proxy = vec!["localhost".to_string()];
+        exported.network.ca_certificates = vec!["/etc/ssl/private-ca.pem".into()];
+        let bundle = export(&exported, None).unwrap();
+
+        let (imported, changed) = import(&Settings::default(), bundle, None).unwrap();
+        assert_eq!(
+            imported.network.proxy.unwrap().as_str(),
+            "http://proxy.example:3128/"
+        );
+        assert_eq!(imported.network.no_proxy, ["localhost"]);
+        assert_eq!(
+            imported.network.ca_certificates,
+            [std::path::PathBuf::from("/etc/ssl/private-ca.pem")]
+        );
+        assert_eq!(
+            changed,
+            [
+                "network.ca_certificates",
+                "network.no_proxy",
+                "network.proxy"
+            ]
+        );
+    }
+}
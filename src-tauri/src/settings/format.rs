@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// File formats settings can be written in. Whatever the format, the contents
+// are handled as JSON values, so migrations work the same for all of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    // In order of precedence, for when more than one settings file exists.
+    pub const ALL: [Format; 3] = [Format::Toml, Format::Yaml, Format::Json];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn file_name(self) -> String {
+        format!("settings.{}", self.extension())
+    }
+
+    // Names of existing settings files in this format, in order of precedence.
+    // New files are always written under `file_name`.
+    pub fn file_names(self) -> &'static [&'static str] {
+        match self {
+            Format::Toml => &["settings.toml"],
+            Format::Yaml => &["settings.yaml", "settings.yml"],
+            Format::Json => &["settings.json"],
+        }
+    }
+
+    // The format of a settings file or one of its backups, e.g.
+    // `settings.toml.bak`. Anything unrecognised is taken to be JSON.
+    pub fn from_path(path: &Path) -> Format {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').skip(1).find_map(|ext| ext.parse().ok()))
+            .unwrap_or(Format::Json)
+    }
+
+    pub fn parse(self, contents: &[u8]) -> Result<Value, String> {
+        match self {
+            Format::Toml => {
+                let contents = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+                toml::from_str(contents).map_err(|e| e.to_string())
+            }
+            Format::Yaml => serde_yaml::from_slice(contents).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_slice(contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn write(self, value: &Value) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Toml => {
+                // TOML has no null, an unset value is a missing key instead.
+                let mut value = value.clone();
+                remove_nulls(&mut value);
+                toml::to_string_pretty(&value)
+                    .map(String::into_bytes)
+                    .map_err(|e| e.to_string())
+            }
+            Format::Yaml => serde_yaml::to_string(value)
+                .map(String::into_bytes)
+                .map_err(|e| e.to_string()),
+            Format::Json => serde_json::to_vec_pretty(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "Unknown settings format \"{}\", expected toml, yaml or json",
+                s
+            )),
+        }
+    }
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, value| !value.is_null());
+            object.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(array) => array.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn detects_formats_from_paths() {
+        let format = |path: &str| Format::from_path(Path::new(path));
+        assert_eq!(format("/config/settings.toml"), Format::Toml);
+        assert_eq!(format("settings.yaml"), Format::Yaml);
+        assert_eq!(format("settings.yml"), Format::Yaml);
+        assert_eq!(format("settings.json"), Format::Json);
+        assert_eq!(format("settings.toml.bak"), Format::Toml);
+        assert_eq!(format("settings.yaml.corrupt"), Format::Yaml);
+        assert_eq!(format("settings"), Format::Json);
+        assert_eq!(format("toml"), Format::Json);
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert_eq!("toml".parse(), Ok(Format::Toml));
+        assert_eq!("yml".parse(), Ok(Format::Yaml));
+        assert_eq!("json".parse(), Ok(Format::Json));
+        assert!("ini".parse::<Format>().is_err());
+        assert!("TOML".parse::<Format>().is_err());
+        for format in Format::ALL {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let value = json!({
+            "schema_version": 10,
+            "active_profile": "default",
+            "profiles": {"default": {"base_url": "http://homeassistant.local:8123/"}},
+            "shortcuts": {"Ctrl+Alt+A": "toggle_window"},
+            "network": {"no_proxy": ["localhost", ".example.com"]},
+        });
+        for format in Format::ALL {
+            let contents = format.write(&value).unwrap();
+            assert_eq!(format.parse(&contents).unwrap(), value, "{}", format);
+        }
+    }
+
+    #[test]
+    fn leaves_nulls_out_of_toml() {
+        let value = json!({
+            "stt": {"endpoint": null, "model": "whisper-1"},
+            "network": {"proxy": null, "no_proxy": []},
+        });
+        let contents = Format::Toml.write(&value).unwrap();
+        assert_eq!(
+            Format::Toml.parse(&contents).unwrap(),
+            json!({"stt": {"model": "whisper-1"}, "network": {"no_proxy": []}})
+        );
+        // Other formats keep them.
+        let contents = Format::Yaml.write(&value).unwrap();
+        assert_eq!(Format::Yaml.parse(&contents).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_invalid_contents() {
+        assert!(Format::Toml.parse(b"profiles = {").is_err());
+        assert!(Format::Toml.parse(&[0xff, 0xfe]).is_err());
+        assert!(Format::Yaml.parse(b"profiles: [").is_err());
+        assert!(Format::Json.parse(b"{\"profiles\":").is_err());
+    }
+}
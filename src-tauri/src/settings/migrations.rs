@@ -0,0 +1,361 @@
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+// Each migration upgrades a settings document from the version matching its
+// index to the next one. To change the schema, add a new function to the end
+// of this list; `CURRENT_SCHEMA_VERSION` follows automatically.
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+    migrate_v5_to_v6,
+    migrate_v6_to_v7,
+    migrate_v7_to_v8,
+    migrate_v8_to_v9,
+];
+
+pub const CURRENT_SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // The document is not something we can migrate at all.
+    Invalid(String),
+    // The document was written by a newer version of the app.
+    Unsupported(u32),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Invalid(message) => write!(f, "{}", message),
+            MigrationError::Unsupported(version) => write!(
+                f,
+                "Settings file has schema version {} but this version of the app only supports up to {}",
+                version, CURRENT_SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+// Upgrade a settings document in place, step by step, to the current schema
+// version. Returns whether anything was changed. Files without a
+// `schema_version` field predate versioning and are treated as version 0.
+pub fn migrate(value: &mut Value) -> Result<bool, MigrationError> {
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| MigrationError::Invalid("Settings file is not a JSON object".to_string()))?;
+
+    let version = match object.get("schema_version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|number| u32::try_from(number).ok())
+            .ok_or_else(|| {
+                MigrationError::Invalid(format!("Invalid schema_version: {}", version))
+            })?,
+    };
+
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(MigrationError::Unsupported(version));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Migrating settings from schema version {}", from);
+        migration(object);
+        object.insert("schema_version".to_string(), json!(from + 1));
+    }
+
+    Ok(version < CURRENT_SCHEMA_VERSION)
+}
+
+// v0 -> v1: `autostart` and `tray` were added after the first release and
+// may be missing or null.
+fn migrate_v0_to_v1(settings: &mut Map<String, Value>) {
+    if !settings.get("autostart").is_some_and(Value::is_boolean) {
+        settings.insert("autostart".to_string(), json!(false));
+    }
+
+    if settings.get("tray").is_none_or(Value::is_null) {
+        settings.insert(
+            "tray".to_string(),
+            json!({ "double_click_action": "toggle_window" }),
+        );
+    }
+}
+
+// v1 -> v2: the single `home_assistant` server became the "default" entry of
+// the named `profiles`.
+fn migrate_v1_to_v2(settings: &mut Map<String, Value>) {
+    let home_assistant = settings
+        .remove("home_assistant")
+        .unwrap_or_else(|| json!({}));
+
+    if !settings.get("profiles").is_some_and(Value::is_object) {
+        settings.insert(
+            "profiles".to_string(),
+            json!({ super::DEFAULT_PROFILE: home_assistant }),
+        );
+    }
+
+    if !settings.get("active_profile").is_some_and(Value::is_string) {
+        settings.insert("active_profile".to_string(), json!(super::DEFAULT_PROFILE));
+    }
+}
+
+// v2 -> v3: `host`, `port` and `ssl` were replaced by a full `base_url`, which
+// can also hold a reverse proxy path prefix.
+fn migrate_v2_to_v3(settings: &mut Map<String, Value>) {
+    let Some(profiles) = settings.get_mut("profiles").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    for profile in profiles.values_mut().filter_map(Value::as_object_mut) {
+        let host = profile.remove("host");
+        let port = profile.remove("port");
+        let ssl = profile.remove("ssl");
+        if profile.contains_key("base_url") {
+            continue;
+        }
+
+        let host = host
+            .as_ref()
+            .and_then(Value::as_str)
+            .unwrap_or("homeassistant.local");
+        let port = port.as_ref().and_then(Value::as_u64).unwrap_or(8123);
+        let ssl = ssl.as_ref().and_then(Value::as_bool).unwrap_or(false);
+
+        profile.insert("base_url".to_string(), json!(base_url(host, port, ssl)));
+    }
+}
+
+// v3 -> v4: the free-form `tray.double_click_action` became a typed action,
+// with a separate action for left clicks, and `quick_commands` were added.
+// Unknown actions used to do nothing, so they become `none`.
+fn migrate_v3_to_v4(settings: &mut Map<String, Value>) {
+    if !settings.get("tray").is_some_and(Value::is_object) {
+        settings.insert("tray".to_string(), json!({}));
+    }
+    let tray = settings
+        .get_mut("tray")
+        .and_then(Value::as_object_mut)
+        .unwrap();
+
+    let double_click = match tray
+        .remove("double_click_action")
+        .as_ref()
+        .and_then(Value::as_str)
+    {
+        Some(action @ ("toggle_window" | "trigger_voice_pipeline")) => action.to_string(),
+        Some(_) => "none".to_string(),
+        None => "toggle_window".to_string(),
+    };
+    tray.entry("double_click").or_insert(json!(double_click));
+    tray.entry("left_click").or_insert(json!("none"));
+
+    if !settings.get("quick_commands").is_some_and(Value::is_object) {
+        settings.insert("quick_commands".to_string(), json!({}));
+    }
+}
+
+// v4 -> v5: the global shortcuts, which used to be fixed, were added to
+// settings.
+fn migrate_v4_to_v5(settings: &mut Map<String, Value>) {
+    if !settings.get("shortcuts").is_some_and(Value::is_object) {
+        settings.insert(
+            "shortcuts".to_string(),
+            json!({
+                "Ctrl+Alt+A": "toggle_window",
+                "Ctrl+Shift+A": "trigger_voice_pipeline",
+            }),
+        );
+    }
+}
+
+// v5 -> v6: speech to text settings were added. The endpoint used to be fixed
+// to an address on the developer's network, so it starts out unset.
+fn migrate_v5_to_v6(settings: &mut Map<String, Value>) {
+    if !settings.get("stt").is_some_and(Value::is_object) {
+        settings.insert(
+            "stt".to_string(),
+            json!({
+                "backend": "openai",
+                "endpoint": null,
+                "model": "whisper-1",
+                "language": null,
+                "timeout_seconds": 30,
+            }),
+        );
+    }
+}
+
+// v6 -> v7: the window position and size are remembered per monitor.
+fn migrate_v6_to_v7(settings: &mut Map<String, Value>) {
+    if !settings.get("window_state").is_some_and(Value::is_object) {
+        settings.insert(
+            "window_state".to_string(),
+            json!({ "last_monitor": null, "monitors": {} }),
+        );
+    }
+}
+
+// v7 -> v8: proxy and CA certificate settings were added.
+fn migrate_v7_to_v8(settings: &mut Map<String, Value>) {
+    if !settings.get("network").is_some_and(Value::is_object) {
+        settings.insert(
+            "network".to_string(),
+            json!({ "proxy": null, "no_proxy": [], "ca_certificates": [] }),
+        );
+    }
+}
+
+// v8 -> v9: profiles can use the REST API only, for servers behind proxies
+// that don't pass on websockets.
+fn migrate_v8_to_v9(settings: &mut Map<String, Value>) {
+    let Some(profiles) = settings.get_mut("profiles").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    for profile in profiles.values_mut().filter_map(Value::as_object_mut) {
+        profile.entry("rest_only").or_insert(json!(false));
+    }
+}
+
+fn base_url(host: &str, port: u64, ssl: bool) -> String {
+    let scheme = if ssl { "https" } else { "http" };
+    // IPv6 literals need brackets in URLs.
+    let host = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    format!("{}://{}:{}/", scheme, host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::Action;
+    use crate::settings::{validate, Settings, SttBackend};
+
+    // Migrate a fixture to the current version and check it loads as settings.
+    fn migrate_fixture(contents: &str) -> Settings {
+        let mut value: Value = serde_json::from_str(contents).unwrap();
+        assert!(migrate(&mut value).unwrap());
+        assert_eq!(value["schema_version"], json!(CURRENT_SCHEMA_VERSION));
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let settings = migrate_fixture(include_str!("../../tests/fixtures/settings/v0.json"));
+        assert!(validate(&settings).is_empty());
+
+        assert!(settings.autostart);
+        assert_eq!(settings.active_profile, "default");
+        let home_assistant = &settings.profiles["default"];
+        assert_eq!(home_assistant.access_token, "token");
+        assert_eq!(home_assistant.base_url.as_str(), "https://ha.example/");
+        assert!(!home_assistant.rest_only);
+        assert_eq!(settings.tray.double_click, Action::TriggerVoicePipeline);
+        assert_eq!(settings.tray.left_click, Action::None);
+        assert_eq!(settings.shortcuts.len(), 2);
+        assert_eq!(settings.stt.endpoint, None);
+    }
+
+    #[test]
+    fn migrates_v0_without_optional_fields() {
+        let settings = migrate_fixture(include_str!(
+            "../../tests/fixtures/settings/v0_minimal.json"
+        ));
+        assert!(validate(&settings).is_empty());
+
+        assert!(!settings.autostart);
+        assert_eq!(
+            settings.profiles["default"].base_url.as_str(),
+            "http://[fe80::1]:8123/"
+        );
+        assert_eq!(settings.tray.double_click, Action::ToggleWindow);
+    }
+
+    #[test]
+    fn migrates_v3() {
+        let settings = migrate_fixture(include_str!("../../tests/fixtures/settings/v3.json"));
+        assert!(validate(&settings).is_empty());
+
+        assert_eq!(settings.active_profile, "work");
+        assert_eq!(
+            settings.profiles["work"].base_url.as_str(),
+            "https://proxy.example/ha/"
+        );
+        // Actions that did nothing stay that way.
+        assert_eq!(settings.tray.double_click, Action::None);
+        assert!(settings.quick_commands.is_empty());
+    }
+
+    #[test]
+    fn migrates_v8() {
+        let settings = migrate_fixture(include_str!("../../tests/fixtures/settings/v8.json"));
+        assert!(validate(&settings).is_empty());
+
+        let home_assistant = &settings.profiles["default"];
+        assert_eq!(
+            home_assistant.access_token_secret.as_deref(),
+            Some("access_token:default")
+        );
+        assert!(!home_assistant.rest_only);
+        assert_eq!(settings.shortcuts["F9"], Action::TriggerVoicePipeline);
+        assert_eq!(settings.stt.backend, SttBackend::WhisperAsr);
+        assert_eq!(settings.network.no_proxy, ["localhost"]);
+    }
+
+    #[test]
+    fn adds_window_state() {
+        let settings = migrate_fixture(include_str!("../../tests/fixtures/settings/v3.json"));
+        assert!(settings.window_state.last_monitor.is_none());
+        assert!(settings.window_state.monitors.is_empty());
+    }
+
+    #[test]
+    fn keeps_window_state() {
+        let settings = migrate_fixture(include_str!("../../tests/fixtures/settings/v8.json"));
+        let window_state = &settings.window_state;
+        assert_eq!(window_state.last_monitor.as_deref(), Some("DELL@3840x2160"));
+        assert_eq!(window_state.monitors["DELL@3840x2160"].width, 620);
+    }
+
+    #[test]
+    fn leaves_current_version_alone() {
+        let mut value = serde_json::to_value(Settings::default()).unwrap();
+        let before = value.clone();
+        assert!(!migrate(&mut value).unwrap());
+        assert_eq!(value, before);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut value = json!({ "schema_version": CURRENT_SCHEMA_VERSION + 1 });
+        assert!(matches!(
+            migrate(&mut value),
+            Err(MigrationError::Unsupported(version)) if version == CURRENT_SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(matches!(
+            migrate(&mut json!([])),
+            Err(MigrationError::Invalid(_))
+        ));
+        assert!(matches!(
+            migrate(&mut json!({ "schema_version": "1" })),
+            Err(MigrationError::Invalid(_))
+        ));
+        // Versions that don't fit in a u32 aren't mistaken for small ones.
+        assert!(matches!(
+            migrate(&mut json!({ "schema_version": u64::from(u32::MAX) + 1 })),
+            Err(MigrationError::Invalid(_))
+        ));
+    }
+}
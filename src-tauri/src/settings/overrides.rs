@@ -0,0 +1,365 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use url::Url;
+
+use super::{HomeAssistantSettings, Settings};
+use crate::cli;
+
+// Where an effective setting came from, lowest precedence first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueSource {
+    // Not customised, the value equals the built-in default.
+    Default,
+    File,
+    Environment,
+    Cli,
+}
+
+// Returned by `get_effective_settings`.
+#[derive(Clone, Serialize)]
+pub struct EffectiveSettings {
+    pub settings: Settings,
+    // Source of each overridable value, keyed by dotted field path like the
+    // validation errors.
+    pub sources: BTreeMap<String, ValueSource>,
+}
+
+// Values from `HA_ASSIST_*` environment variables and CLI flags, layered on
+// top of settings.json for the active profile without ever being written to it.
+#[derive(Clone, Default)]
+pub struct Overrides {
+    base_url: Option<(Url, ValueSource)>,
+    // Replace parts of the base URL, for scripts written against the old
+    // host, port and ssl settings.
+    host: Option<(String, ValueSource)>,
+    port: Option<(u16, ValueSource)>,
+    ssl: Option<(bool, ValueSource)>,
+    access_token: Option<(String, ValueSource)>,
+}
+
+impl Overrides {
+    pub fn from_env_and_args(args: &[String]) -> Result<Self, String> {
+        Self::parse(|name| std::env::var(name).ok(), args)
+    }
+
+    fn parse(env: impl Fn(&str) -> Option<String>, args: &[String]) -> Result<Self, String> {
+        let mut overrides = Overrides::default();
+
+        // Environment variables
+        let source = ValueSource::Environment;
+        if let Some(base_url) = env("HA_ASSIST_URL") {
+            overrides.base_url = Some((parse_url("HA_ASSIST_URL", &base_url)?, source));
+        }
+        if let Some(host) = env("HA_ASSIST_HOST") {
+            overrides.host = Some((parse_host("HA_ASSIST_HOST", &host)?, source));
+        }
+        if let Some(port) = env("HA_ASSIST_PORT") {
+            overrides.port = Some((parse_port("HA_ASSIST_PORT", &port)?, source));
+        }
+        if let Some(ssl) = env("HA_ASSIST_SSL") {
+            overrides.ssl = Some((parse_bool("HA_ASSIST_SSL", &ssl)?, source));
+        }
+        if let Some(path) = env("HA_ASSIST_TOKEN_FILE") {
+            overrides.access_token = Some((read_token_file(&path)?, source));
+        }
+        if let Some(token) = env("HA_ASSIST_TOKEN") {
+            overrides.access_token = Some((token.trim().to_string(), source));
+        }
+
+        // CLI flags take precedence over the environment. There is no flag
+        // for the token itself, as other users can see process arguments.
+        let source = ValueSource::Cli;
+        if let Some(base_url) = cli::value(args, "--url") {
+            overrides.base_url = Some((parse_url("--url", &base_url)?, source));
+        }
+        if let Some(host) = cli::value(args, "--host") {
+            overrides.host = Some((parse_host("--host", &host)?, source));
+        }
+        if let Some(port) = cli::value(args, "--port") {
+            overrides.port = Some((parse_port("--port", &port)?, source));
+        }
+        if let Some(ssl) = args.iter().find_map(|arg| arg.strip_prefix("--ssl=")) {
+            overrides.ssl = Some((parse_bool("--ssl", ssl)?, source));
+        } else if cli::has(args, "--ssl") {
+            overrides.ssl = Some((true, source));
+        } else if cli::has(args, "--no-ssl") {
+            overrides.ssl = Some((false, source));
+        }
+        if let Some(path) = cli::value(args, "--token-file") {
+            overrides.access_token = Some((read_token_file(&path)?, source));
+        }
+
+        Ok(overrides)
+    }
+
+    // Layer the overrides on top of settings loaded from the file.
+    pub fn apply(&self, settings: &Settings) -> EffectiveSettings {
+        let mut settings = settings.clone();
+        let mut sources: BTreeMap<String, ValueSource> = BTreeMap::new();
+
+        let defaults = HomeAssistantSettings::default();
+        let prefix = format!("profiles.{}", settings.active_profile);
+        if let Some(home_assistant) = settings.profiles.get_mut(&settings.active_profile) {
+            let mut layer = |field: &str, source: ValueSource| {
+                sources.insert(format!("{}.{}", prefix, field), source);
+            };
+            let mut source = apply(
+                &mut home_assistant.base_url,
+                &defaults.base_url,
+                &self.base_url,
+            );
+            let base_url = &mut home_assistant.base_url;
+            if let Some((host, host_source)) = &self.host {
+                if base_url.set_host(Some(host)).is_ok() {
+                    source = source.max(*host_source);
+                }
+            }
+            if let Some((port, port_source)) = &self.port {
+                if base_url.set_port(Some(*port)).is_ok() {
+                    source = source.max(*port_source);
+                }
+            }
+            if let Some((ssl, ssl_source)) = &self.ssl {
+                let scheme = if *ssl { "https" } else { "http" };
+                if base_url.set_scheme(scheme).is_ok() {
+                    source = source.max(*ssl_source);
+                }
+            }
+            layer("base_url", source);
+            layer(
+                "access_token",
+                apply(
+                    &mut home_assistant.access_token,
+                    &defaults.access_token,
+                    &self.access_token,
+                ),
+            );
+        }
+
+        EffectiveSettings { settings, sources }
+    }
+}
+
+fn apply<T: Clone + PartialEq>(
+    value: &mut T,
+    default: &T,
+    layer: &Option<(T, ValueSource)>,
+) -> ValueSource {
+    match layer {
+        Some((override_value, source)) => {
+            *value = override_value.clone();
+            *source
+        }
+        None if value == default => ValueSource::Default,
+        None => ValueSource::File,
+    }
+}
+
+fn parse_port(name: &str, value: &str) -> Result<u16, String> {
+    match value.trim().parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(format!(
+            "{} must be a port between 1 and 65535, got \"{}\"",
+            name, value
+        )),
+    }
+}
+
+fn parse_url(name: &str, value: &str) -> Result<Url, String> {
+    Url::parse(value.trim())
+        .map_err(|e| format!("{} must be a URL, got \"{}\": {}", name, value, e))
+}
+
+// IPv6 literals are accepted with or without brackets.
+fn parse_host(name: &str, value: &str) -> Result<String, String> {
+    let host = value.trim();
+    let host = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    url::Host::parse(&host).map(|_| host).map_err(|e| {
+        format!(
+            "{} must be a host name or IP address, got \"{}\": {}",
+            name, value, e
+        )
+    })
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("{} must be true or false, got \"{}\"", name, value)),
+    }
+}
+
+fn read_token_file(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|token| token.trim().to_string())
+        .map_err(|e| format!("Could not read token file {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(env: &[(&str, &str)], args: &[&str]) -> Result<Overrides, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let args: Vec<String> = std::iter::once("app")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect();
+        Overrides::parse(|name| env.get(name).cloned(), &args)
+    }
+
+    fn base_url(overrides: &Overrides) -> String {
+        let effective = overrides.apply(&Settings::default());
+        effective.settings.profiles["default"].base_url.to_string()
+    }
+
+    #[test]
+    fn parses_nothing() {
+        let overrides = parse(&[], &[]).unwrap();
+        let effective = overrides.apply(&Settings::default());
+        assert_eq!(
+            effective.sources["profiles.default.base_url"],
+            ValueSource::Default
+        );
+        assert_eq!(
+            effective.sources["profiles.default.access_token"],
+            ValueSource::Default
+        );
+    }
+
+    #[test]
+    fn parses_environment() {
+        let overrides = parse(
+            &[
+                ("HA_ASSIST_URL", " https://ha.example/prefix/ "),
+                ("HA_ASSIST_TOKEN", "token\n"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            overrides.base_url,
+            Some((
+                Url::parse("https://ha.example/prefix/").unwrap(),
+                ValueSource::Environment
+            ))
+        );
+        assert_eq!(
+            overrides.access_token,
+            Some(("token".to_string(), ValueSource::Environment))
+        );
+    }
+
+    #[test]
+    fn prefers_cli_flags() {
+        let overrides = parse(
+            &[
+                ("HA_ASSIST_URL", "http://env.example/"),
+                ("HA_ASSIST_PORT", "1"),
+            ],
+            &["--url=http://cli.example/", "--port", "2"],
+        )
+        .unwrap();
+        assert_eq!(
+            overrides.base_url,
+            Some((Url::parse("http://cli.example/").unwrap(), ValueSource::Cli))
+        );
+        assert_eq!(overrides.port, Some((2, ValueSource::Cli)));
+    }
+
+    #[test]
+    fn parses_ssl_flags() {
+        let ssl = |args: &[&str]| parse(&[("HA_ASSIST_SSL", "off")], args).unwrap().ssl;
+        assert_eq!(ssl(&[]), Some((false, ValueSource::Environment)));
+        assert_eq!(ssl(&["--ssl"]), Some((true, ValueSource::Cli)));
+        assert_eq!(ssl(&["--no-ssl"]), Some((false, ValueSource::Cli)));
+        assert_eq!(ssl(&["--ssl=yes"]), Some((true, ValueSource::Cli)));
+        assert_eq!(ssl(&["--ssl=0"]), Some((false, ValueSource::Cli)));
+    }
+
+    #[test]
+    fn reads_token_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("token");
+        std::fs::write(&path, "file token\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let overrides = parse(&[("HA_ASSIST_TOKEN_FILE", path)], &[]).unwrap();
+        assert_eq!(
+            overrides.access_token,
+            Some(("file token".to_string(), ValueSource::Environment))
+        );
+
+        // The token itself wins over a token file in the environment.
+        let overrides = parse(
+            &[("HA_ASSIST_TOKEN_FILE", path), ("HA_ASSIST_TOKEN", "token")],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            overrides.access_token,
+            Some(("token".to_string(), ValueSource::Environment))
+        );
+
+        let overrides = parse(&[("HA_ASSIST_TOKEN", "token")], &["--token-file", path]).unwrap();
+        assert_eq!(
+            overrides.access_token,
+            Some(("file token".to_string(), ValueSource::Cli))
+        );
+
+        let missing = directory.path().join("missing");
+        assert!(parse(&[], &["--token-file", missing.to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse(&[("HA_ASSIST_URL", "ha.example")], &[]).is_err());
+        assert!(parse(&[("HA_ASSIST_PORT", "0")], &[]).is_err());
+        assert!(parse(&[], &["--port=65536"]).is_err());
+        assert!(parse(&[], &["--host", "bad host"]).is_err());
+        assert!(parse(&[("HA_ASSIST_SSL", "maybe")], &[]).is_err());
+    }
+
+    #[test]
+    fn applies_parts_of_the_base_url() {
+        let overrides = parse(
+            &[("HA_ASSIST_HOST", "fe80::1"), ("HA_ASSIST_SSL", "true")],
+            &["--port", "8443"],
+        )
+        .unwrap();
+        assert_eq!(base_url(&overrides), "https://[fe80::1]:8443/");
+
+        let effective = overrides.apply(&Settings::default());
+        assert_eq!(
+            effective.sources["profiles.default.base_url"],
+            ValueSource::Cli
+        );
+    }
+
+    #[test]
+    fn applies_to_the_active_profile_only() {
+        let mut settings = Settings::default();
+        settings
+            .add_profile("work", HomeAssistantSettings::default())
+            .unwrap();
+        settings.activate_profile("work").unwrap();
+
+        let overrides = parse(&[("HA_ASSIST_TOKEN", "token")], &[]).unwrap();
+        let effective = overrides.apply(&settings);
+        assert_eq!(effective.settings.profiles["work"].access_token, "token");
+        assert_eq!(effective.settings.profiles["default"].access_token, "");
+        assert!(!effective
+            .sources
+            .contains_key("profiles.default.access_token"));
+    }
+}
@@ -0,0 +1,301 @@
+use notify::{RecursiveMode, Watcher};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use super::{EffectiveSettings, Format, Overrides, Recovery, Settings};
+
+// How long to wait for an editor to finish writing before reloading.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeSource {
+    // Saved from within the app, e.g. the settings page or the tray.
+    App,
+    // The settings file was edited outside of the app.
+    File,
+}
+
+// Payload of the `settings-changed` event. Carries the effective settings,
+// i.e. including environment and CLI overrides.
+#[derive(Clone, Serialize)]
+pub struct SettingsChanged {
+    pub source: ChangeSource,
+    pub settings: Settings,
+}
+
+// Settings held in memory for the lifetime of the app. Reads never touch the
+// disk; every write goes through `set` so the file and memory stay in step.
+pub struct SettingsStore {
+    // Changes when the file is converted to another format.
+    path: RwLock<PathBuf>,
+    // As stored in the file, without overrides.
+    settings: RwLock<Settings>,
+    overrides: Overrides,
+    // Set when the file had to be recovered on startup, until the webview has
+    // been told about it.
+    recovery: Mutex<Option<Recovery>>,
+    // Contents of our last write, so the watcher can tell it apart from
+    // changes made outside of the app without reading secrets again.
+    written: Mutex<Option<Vec<u8>>>,
+}
+
+impl SettingsStore {
+    pub fn load(path: PathBuf, overrides: Overrides) -> Result<Self, String> {
+        println!("Loading settings from {}...", path.display());
+
+        let (settings, recovery) = super::load(&path)?;
+
+        Ok(SettingsStore {
+            path: RwLock::new(path),
+            settings: RwLock::new(settings),
+            overrides,
+            recovery: Mutex::new(recovery),
+            written: Mutex::new(None),
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.path.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Settings as stored in the file, for editing.
+    pub fn get(&self) -> Settings {
+        self.read(Settings::clone)
+    }
+
+    // Settings the app should actually use.
+    pub fn effective(&self) -> EffectiveSettings {
+        self.read(|settings| self.overrides.apply(settings))
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&Settings) -> R) -> R {
+        f(&self.settings.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn set(&self, settings: Settings) -> Result<(), String> {
+        let path = self.path();
+        println!("Updating settings at {}...", path.display());
+
+        // Hold the lock while writing so concurrent saves can't interleave.
+        // What was written is kept, so `reload` doesn't take our own write
+        // for a change.
+        let mut current = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        self.write(&mut current, &path, &settings, true)
+    }
+
+    // Change the stored settings in place, for state the app keeps itself
+    // such as where the window is. That changes often and isn't an edit worth
+    // rolling back to, so the backup of the file is left as it is. The lock is
+    // held throughout so no concurrent change is lost.
+    pub fn update(&self, f: impl FnOnce(&mut Settings)) -> Result<(), String> {
+        let mut current = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        let mut settings = current.clone();
+        f(&mut settings);
+        self.write(&mut current, &self.path(), &settings, false)
+    }
+
+    fn write(
+        &self,
+        current: &mut Settings,
+        path: &Path,
+        settings: &Settings,
+        backup: bool,
+    ) -> Result<(), String> {
+        let (settings, contents) = super::write_settings(path, settings, backup)?;
+        *current = settings;
+        *self.written.lock().unwrap_or_else(|e| e.into_inner()) = Some(contents);
+
+        Ok(())
+    }
+
+    // Re-read the file after it changed on disk. Returns the new settings if
+    // they differ from the ones in memory, which is not the case when the
+    // change was our own write.
+    pub fn reload(&self) -> Result<Option<Settings>, String> {
+        let path = self.path();
+        let written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        if written.is_some() && std::fs::read(&path).ok() == *written {
+            return Ok(None);
+        }
+        drop(written);
+        let settings = super::reload(&path)?;
+
+        let mut current = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        if serde_json::to_value(&settings).ok() == serde_json::to_value(&*current).ok() {
+            return Ok(None);
+        }
+        *current = settings.clone();
+
+        Ok(Some(settings))
+    }
+
+    // Switch the settings file to another format, see `super::convert`.
+    pub fn convert(&self, format: Format) -> Result<PathBuf, String> {
+        // Hold the lock so nothing is saved to the old file in the meantime.
+        let _settings = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        let mut path = self.path.write().unwrap_or_else(|e| e.into_inner());
+        *path = super::convert(&path, format)?;
+
+        Ok(path.clone())
+    }
+
+    pub fn take_recovery(&self) -> Option<Recovery> {
+        self.recovery
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
+
+// Watch the settings file for changes made outside of the app and call
+// `on_change` once writes have settled. The directory is watched rather than
+// the file itself, as editors usually replace the file instead of writing it,
+// and the file may be converted to another format while the app runs.
+pub fn watch(path: PathBuf, on_change: impl Fn() + Send + 'static) {
+    std::thread::spawn(move || {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                log::warn!("Could not watch settings for changes: {}", e);
+                return;
+            }
+        };
+
+        let Some(directory) = path.parent() else {
+            return;
+        };
+        if let Err(e) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            log::warn!("Could not watch {}: {}", directory.display(), e);
+            return;
+        }
+
+        log::info!("Watching {} for changes", path.display());
+
+        while let Ok(event) = rx.recv() {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("Settings watcher error: {}", e);
+                    continue;
+                }
+            };
+
+            if event.kind.is_access()
+                || !event
+                    .paths
+                    .iter()
+                    .any(|changed| super::is_settings_file(changed))
+            {
+                continue;
+            }
+
+            // Let the writer finish, then handle the burst of events as one.
+            std::thread::sleep(WATCH_DEBOUNCE);
+            while rx.try_recv().is_ok() {}
+
+            on_change();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretStore;
+    use crate::settings::{backup_path, reload, SttSettings, DEFAULT_PROFILE};
+
+    #[test]
+    fn does_not_take_its_own_writes_for_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let store = SettingsStore::load(path.clone(), Overrides::default()).unwrap();
+
+        let mut settings = store.get();
+        settings.autostart = true;
+        store.set(settings).unwrap();
+        assert!(store.reload().unwrap().is_none());
+
+        store
+            .update(|settings| settings.stt = SttSettings::default())
+            .unwrap();
+        assert!(store.reload().unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_the_backup_when_the_app_updates_its_state() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let store = SettingsStore::load(path.clone(), Overrides::default()).unwrap();
+
+        let mut settings = store.get();
+        settings.autostart = true;
+        store.set(settings.clone()).unwrap();
+        settings.autostart = false;
+        store.set(settings).unwrap();
+        let backup = std::fs::read(backup_path(&path)).unwrap();
+
+        store
+            .update(|settings| settings.window_state.last_monitor = Some("DELL".to_string()))
+            .unwrap();
+        assert_eq!(std::fs::read(backup_path(&path)).unwrap(), backup);
+        assert!(store.reload().unwrap().is_none());
+        assert_eq!(
+            reload(&path).unwrap().window_state.last_monitor.as_deref(),
+            Some("DELL")
+        );
+    }
+
+    #[test]
+    fn does_not_take_changed_secrets_for_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let mut settings = Settings::default();
+        // In the encrypted file, so the test never touches the keyring.
+        let reference = SecretStore::new(directory.path())
+            .write_to_file("profiles/default/access_token", "token")
+            .unwrap();
+        settings
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .access_token_secret = Some(reference);
+        std::fs::write(&path, serde_json::to_vec(&settings).unwrap()).unwrap();
+        let store = SettingsStore::load(path.clone(), Overrides::default()).unwrap();
+
+        // Clearing the token removes the reference to it from the file, while
+        // the settings page still sends the old one.
+        let mut settings = store.get();
+        settings
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .access_token
+            .clear();
+        store.set(settings).unwrap();
+        assert!(store.get().profiles[DEFAULT_PROFILE]
+            .access_token_secret
+            .is_none());
+        assert!(store.reload().unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_changes_made_outside_of_the_app() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("settings.json");
+        let store = SettingsStore::load(path.clone(), Overrides::default()).unwrap();
+
+        let mut settings = store.get();
+        settings.autostart = true;
+        std::fs::write(&path, serde_json::to_vec(&settings).unwrap()).unwrap();
+        assert!(store
+            .reload()
+            .unwrap()
+            .is_some_and(|settings| settings.autostart));
+        assert!(store.reload().unwrap().is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tauri::{GlobalShortcutManager, Manager};
+
+use crate::actions::Action;
+
+// The outcome of registering one shortcut from settings. Sent to the webview
+// as the `shortcut-registration-failed` event when `error` is set.
+#[derive(Clone, Serialize)]
+pub struct ShortcutRegistration {
+    pub accelerator: String,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// The shortcuts as last registered, including the ones that failed, so the
+// tray and the settings page can show the live bindings.
+#[derive(Default)]
+pub struct Shortcuts(Mutex<Vec<ShortcutRegistration>>);
+
+impl Shortcuts {
+    pub fn registrations(&self) -> Vec<ShortcutRegistration> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// Replace all registered global shortcuts with the ones from settings. A
+// shortcut that can't be registered, e.g. because another app already uses
+// it, is reported and skipped rather than stopping the others.
+pub fn register(app_handle: &tauri::AppHandle, shortcuts: &BTreeMap<String, Action>) {
+    let mut manager = app_handle.global_shortcut_manager();
+    if let Err(e) = manager.unregister_all() {
+        log::warn!("Could not unregister shortcuts: {}", e);
+    }
+
+    let registrations = plan(shortcuts, |accelerator, action| {
+        let handle = app_handle.clone();
+        let action = action.clone();
+        manager
+            .register(accelerator, move || crate::run_action(&handle, &action))
+            .map_err(|e| e.to_string())
+    });
+
+    for registration in &registrations {
+        match &registration.error {
+            None => log::info!(
+                "Registered {} shortcut for {}",
+                registration.accelerator,
+                registration.action
+            ),
+            Some(e) => {
+                log::warn!(
+                    "Could not register {} shortcut: {}",
+                    registration.accelerator,
+                    e
+                );
+                if let Err(e) = app_handle.emit_all("shortcut-registration-failed", registration) {
+                    log::warn!("Could not emit shortcut-registration-failed event: {}", e);
+                }
+            }
+        }
+    }
+
+    *app_handle.state::<Shortcuts>().0.lock().unwrap() = registrations;
+}
+
+// Register each shortcut with `register`, skipping the ones bound to nothing.
+// Invalid accelerators and ones that are the same as an earlier one, e.g.
+// `alt+ctrl+a` after `Ctrl+Alt+A`, fail without being passed on.
+fn plan(
+    shortcuts: &BTreeMap<String, Action>,
+    mut register: impl FnMut(&str, &Action) -> Result<(), String>,
+) -> Vec<ShortcutRegistration> {
+    let mut registered: BTreeMap<String, &str> = BTreeMap::new();
+    let mut registrations = Vec::new();
+    for (accelerator, action) in shortcuts {
+        if *action == Action::None {
+            continue;
+        }
+
+        let result = normalize(accelerator).and_then(|normalized| {
+            if let Some(other) = registered.get(&normalized) {
+                return Err(format!("Same shortcut as {}", other));
+            }
+            register(accelerator, action)?;
+            registered.insert(normalized, accelerator);
+            Ok(())
+        });
+
+        registrations.push(ShortcutRegistration {
+            accelerator: accelerator.clone(),
+            action: action.clone(),
+            error: result.err(),
+        });
+    }
+    registrations
+}
+
+// Accelerators are case insensitive and their modifiers can come in any
+// order, so e.g. `Ctrl+Alt+A` and `alt+ctrl+a` both become `alt+ctrl+A`.
+// Whether the key exists is left to the system.
+pub fn normalize(accelerator: &str) -> Result<String, String> {
+    let mut modifiers = Vec::new();
+    let mut key = None;
+    for part in accelerator.split('+').map(str::trim) {
+        let modifier = match part.to_lowercase().as_str() {
+            "" => return Err(format!("Invalid shortcut {}, empty key", accelerator)),
+            "ctrl" | "control" => "ctrl",
+            "alt" | "option" => "alt",
+            "shift" => "shift",
+            "super" | "cmd" | "command" | "meta" => "super",
+            "cmdorctrl" | "commandorcontrol" => "cmdorctrl",
+            _ => {
+                if let Some(other) = key.replace(part.to_uppercase()) {
+                    return Err(format!(
+                        "Invalid shortcut {}, it has more than one key ({} and {})",
+                        accelerator, other, part
+                    ));
+                }
+                continue;
+            }
+        };
+        modifiers.push(modifier);
+    }
+
+    let key = key.ok_or_else(|| format!("Invalid shortcut {}, it has no key", accelerator))?;
+    modifiers.sort_unstable();
+    modifiers.dedup();
+    modifiers.push(&key);
+    Ok(modifiers.join("+"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_accelerators() {
+        assert_eq!(normalize("F9").unwrap(), "F9");
+        assert_eq!(normalize("Ctrl+Alt+A").unwrap(), "alt+ctrl+A");
+        assert_eq!(normalize("alt + control + a").unwrap(), "alt+ctrl+A");
+        assert_eq!(
+            normalize("CmdOrCtrl+Shift+Space").unwrap(),
+            "cmdorctrl+shift+SPACE"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_accelerators() {
+        assert!(normalize("").is_err());
+        assert!(normalize("Ctrl+").unwrap_err().contains("empty key"));
+        assert!(normalize("Ctrl+Alt").unwrap_err().contains("no key"));
+        assert!(normalize("Ctrl+A+B")
+            .unwrap_err()
+            .contains("more than one key"));
+    }
+
+    #[test]
+    fn registers_shortcuts_without_modifiers() {
+        let shortcuts = BTreeMap::from([
+            ("F9".to_string(), Action::TriggerVoicePipeline),
+            ("F10".to_string(), Action::None),
+        ]);
+        let mut registered = Vec::new();
+        let registrations = plan(&shortcuts, |accelerator, _| {
+            registered.push(accelerator.to_string());
+            Ok(())
+        });
+        assert_eq!(registered, ["F9"]);
+        assert_eq!(registrations.len(), 1);
+        assert!(registrations[0].error.is_none());
+    }
+
+    #[test]
+    fn reports_duplicate_and_conflicting_shortcuts() {
+        let shortcuts = BTreeMap::from([
+            ("Ctrl+Alt+A".to_string(), Action::ToggleWindow),
+            ("Ctrl+Shift+A".to_string(), Action::TriggerVoicePipeline),
+            ("alt+ctrl+a".to_string(), Action::OpenSettings),
+            ("Ctrl++".to_string(), Action::NewConversation),
+        ]);
+        let mut registered = Vec::new();
+        let registrations = plan(&shortcuts, |accelerator, _| {
+            // As if another app already had it.
+            if accelerator == "Ctrl+Shift+A" {
+                return Err("already registered".to_string());
+            }
+            registered.push(accelerator.to_string());
+            Ok(())
+        });
+        assert_eq!(registered, ["Ctrl+Alt+A"]);
+
+        let errors: Vec<(&str, Option<&str>)> = registrations
+            .iter()
+            .map(|registration| {
+                (
+                    registration.accelerator.as_str(),
+                    registration.error.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("Ctrl++", Some("Invalid shortcut Ctrl++, empty key")),
+                ("Ctrl+Alt+A", None),
+                ("Ctrl+Shift+A", Some("already registered")),
+                ("alt+ctrl+a", Some("Same shortcut as Ctrl+Alt+A")),
+            ]
+        );
+
+        // Failed registrations are sent to the webview with their error.
+        let event = serde_json::to_value(&registrations[2]).unwrap();
+        assert_eq!(event["accelerator"], "Ctrl+Shift+A");
+        assert_eq!(event["error"], "already registered");
+        assert!(serde_json::to_value(&registrations[1])
+            .unwrap()
+            .get("error")
+            .is_none());
+    }
+}
@@ -0,0 +1,305 @@
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+use crate::settings::{NetworkSettings, SttBackend, SttSettings};
+
+// Both backends answer with at least `{ "text": "..." }`.
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+// Send mono 16-bit PCM to the configured speech to text backend and return
+// the transcribed text.
+pub async fn transcribe(
+    settings: &SttSettings,
+    network: &NetworkSettings,
+    samples: &[i16],
+    sample_rate: u32,
+) -> Result<String, HttpError> {
+    let endpoint = settings
+        .endpoint
+        .as_ref()
+        .ok_or_else(|| "Speech to text is not set up, add an endpoint in settings".to_string())?;
+
+    let client = http::client(network, Duration::from_secs(settings.timeout_seconds))?;
+
+    let file = Part::bytes(wav(samples, sample_rate))
+        .file_name("audio.wav")
+        .mime_str("audio/wav")
+        .map_err(|e| e.to_string())?;
+
+    let mut request = match settings.backend {
+        SttBackend::OpenAi => {
+            let mut form = Form::new()
+                .part("file", file)
+                .text("model", settings.model.clone())
+                .text("response_format", "json");
+            if let Some(language) = &settings.language {
+                form = form.text("language", language.clone());
+            }
+            client.post(endpoint.clone()).multipart(form)
+        }
+        SttBackend::WhisperAsr => {
+            let mut url = endpoint.clone();
+            url.query_pairs_mut().append_pair("output", "json");
+            if let Some(language) = &settings.language {
+                url.query_pairs_mut().append_pair("language", language);
+            }
+            client
+                .post(url)
+                .multipart(Form::new().part("audio_file", file))
+        }
+    };
+    if !settings.api_key.is_empty() {
+        request = request.bearer_auth(&settings.api_key);
+    }
+
+    log::info!(
+        "Transcribing {} samples at {}Hz with {}",
+        samples.len(),
+        sample_rate,
+        endpoint
+    );
+    let response = http::send(request, "speech to text server").await?;
+
+    let transcription: TranscriptionResponse = response
+        .json()
+        .await
+        .map_err(|e| format!("Invalid response from speech to text server: {}", e))?;
+
+    Ok(transcription.text.trim().to_string())
+}
+
+// Wrap PCM samples in a WAV header, which every backend accepts.
+fn wav(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    let data_length = (samples.len() * 2) as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_length as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_length).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM format
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+    use url::Url;
+
+    // What the stand-in server received.
+    struct Request {
+        head: String,
+        body: String,
+    }
+
+    // A stand-in for a speech to text server, answering every request with
+    // `status` and `body`, or not at all without a status.
+    async fn serve(
+        status: Option<u16>,
+        body: &'static str,
+    ) -> (Url, mpsc::UnboundedReceiver<Request>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/v1/audio/transcriptions",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut data = Vec::new();
+                let mut buffer = [0; 4096];
+                let (head, length) = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    data.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&data);
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let head = text[..end].to_string();
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|value| value.parse().unwrap())
+                            })
+                            .unwrap();
+                        data.drain(..end + 4);
+                        break (head, length);
+                    }
+                };
+                while data.len() < length {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    data.extend_from_slice(&buffer[..read]);
+                }
+                sender
+                    .send(Request {
+                        head,
+                        body: String::from_utf8_lossy(&data).to_string(),
+                    })
+                    .unwrap();
+
+                let Some(status) = status else {
+                    // Keep the connection open without answering.
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        drop(stream);
+                    });
+                    continue;
+                };
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn stt_settings(backend: SttBackend, endpoint: Url) -> SttSettings {
+        SttSettings {
+            backend,
+            endpoint: Some(endpoint),
+            timeout_seconds: 1,
+            ..SttSettings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transcribes_with_openai() {
+        let (endpoint, mut requests) = serve(
+            Some(200),
+            r#"{ "text": " Turn on the lights ", "language": "en" }"#,
+        )
+        .await;
+        let mut settings = stt_settings(SttBackend::OpenAi, endpoint);
+        settings.language = Some("en".to_string());
+        settings.api_key = "key".to_string();
+
+        let text = transcribe(&settings, &NetworkSettings::default(), &[0, 1, -1], 16000)
+            .await
+            .unwrap();
+        assert_eq!(text, "Turn on the lights");
+
+        let request = requests.recv().await.unwrap();
+        assert!(request
+            .head
+            .starts_with("POST /v1/audio/transcriptions HTTP/1.1"));
+        assert!(request.head.contains("authorization: Bearer key"));
+        for field in [
+            r#"name="file"; filename="audio.wav""#,
+            r#"name="model""#,
+            "whisper-1",
+            r#"name="response_format""#,
+            r#"name="language""#,
+            "RIFF",
+        ] {
+            assert!(request.body.contains(field), "{} not in request", field);
+        }
+    }
+
+    #[tokio::test]
+    async fn transcribes_with_whisper_asr() {
+        let (mut endpoint, mut requests) = serve(Some(200), r#"{ "text": "Hello" }"#).await;
+        endpoint.set_path("/asr");
+        let mut settings = stt_settings(SttBackend::WhisperAsr, endpoint);
+        settings.language = Some("de".to_string());
+
+        let text = transcribe(&settings, &NetworkSettings::default(), &[0; 160], 16000)
+            .await
+            .unwrap();
+        assert_eq!(text, "Hello");
+
+        let request = requests.recv().await.unwrap();
+        assert!(request
+            .head
+            .starts_with("POST /asr?output=json&language=de HTTP/1.1"));
+        assert!(!request.head.contains("authorization"));
+        assert!(request
+            .body
+            .contains(r#"name="audio_file"; filename="audio.wav""#));
+        assert!(!request.body.contains(r#"name="model""#));
+    }
+
+    #[tokio::test]
+    async fn reports_server_errors() {
+        let (endpoint, _requests) = serve(Some(500), "model not loaded").await;
+        let settings = stt_settings(SttBackend::OpenAi, endpoint);
+        let error = transcribe(&settings, &NetworkSettings::default(), &[0], 16000)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, HttpError::Other(message) if message.contains("model not loaded"))
+        );
+
+        let (endpoint, _requests) = serve(Some(401), "").await;
+        let settings = stt_settings(SttBackend::OpenAi, endpoint);
+        let error = transcribe(&settings, &NetworkSettings::default(), &[0], 16000)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, HttpError::Auth(_)));
+
+        let (endpoint, _requests) = serve(Some(200), "<html>").await;
+        let settings = stt_settings(SttBackend::OpenAi, endpoint);
+        let error = transcribe(&settings, &NetworkSettings::default(), &[0], 16000)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Invalid response"));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let (endpoint, _requests) = serve(None, "").await;
+        let settings = stt_settings(SttBackend::OpenAi, endpoint);
+        let error = transcribe(&settings, &NetworkSettings::default(), &[0], 16000)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("did not respond in time"));
+    }
+
+    #[tokio::test]
+    async fn requires_an_endpoint() {
+        let error = transcribe(
+            &SttSettings::default(),
+            &NetworkSettings::default(),
+            &[0],
+            16000,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("add an endpoint"));
+    }
+
+    #[test]
+    fn writes_wav_headers() {
+        let wav = wav(&[1, -2], 16000);
+        assert_eq!(wav.len(), 44 + 4);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 4);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 16000);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 4);
+        assert_eq!(&wav[44..], [1, 0, 0xfe, 0xff]);
+    }
+}
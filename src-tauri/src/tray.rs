@@ -0,0 +1,182 @@
+use tauri::{CustomMenuItem, Manager, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu};
+
+use crate::actions::Action;
+use crate::home_assistant::{ConnectionStatus, HomeAssistantClient};
+use crate::settings::Settings;
+use crate::shortcuts::{ShortcutRegistration, Shortcuts};
+
+// Menu item ids for profiles are prefixed so they can't clash with the fixed items.
+pub const PROFILE_ITEM_PREFIX: &str = "profile:";
+
+// The disabled item at the top showing the state of the connection.
+const CONNECTION_STATUS_ITEM: &str = "connection_status";
+
+// A menu item running `action`, labelled with the shortcut bound to it, if any.
+fn action_item(action: Action, label: &str, shortcuts: &[ShortcutRegistration]) -> CustomMenuItem {
+    let label = action_label(&action, label, shortcuts);
+    CustomMenuItem::new(action.to_string(), label)
+}
+
+// Only shortcuts that were actually registered are shown.
+fn action_label(action: &Action, label: &str, shortcuts: &[ShortcutRegistration]) -> String {
+    let shortcut = shortcuts
+        .iter()
+        .find(|shortcut| shortcut.error.is_none() && shortcut.action == *action);
+    match shortcut {
+        Some(shortcut) => format!("{} ({})", label, shortcut.accelerator),
+        None => label.to_string(),
+    }
+}
+
+pub fn build_menu(
+    settings: &Settings,
+    shortcuts: &[ShortcutRegistration],
+    status: &ConnectionStatus,
+) -> SystemTrayMenu {
+    let mut server_menu: SystemTrayMenu = SystemTrayMenu::new();
+    for name in settings.profiles.keys() {
+        let mut item = CustomMenuItem::new(format!("{}{}", PROFILE_ITEM_PREFIX, name), name);
+        if *name == settings.active_profile {
+            item = item.selected();
+        }
+        server_menu = server_menu.add_item(item);
+    }
+
+    let mut menu = SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new(CONNECTION_STATUS_ITEM, status.to_string()).disabled())
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(action_item(
+            Action::ToggleWindow,
+            "Show/Hide Window",
+            shortcuts,
+        ))
+        .add_item(action_item(
+            Action::TriggerVoicePipeline,
+            "Trigger Voice Pipeline",
+            shortcuts,
+        ))
+        .add_item(action_item(
+            Action::NewConversation,
+            "New Conversation",
+            shortcuts,
+        ));
+
+    if !settings.quick_commands.is_empty() {
+        let mut quick_command_menu = SystemTrayMenu::new();
+        for name in settings.quick_commands.keys() {
+            quick_command_menu = quick_command_menu.add_item(action_item(
+                Action::QuickCommand(name.clone()),
+                name,
+                shortcuts,
+            ));
+        }
+        menu = menu.add_submenu(SystemTraySubmenu::new("Quick Commands", quick_command_menu));
+    }
+
+    menu.add_native_item(SystemTrayMenuItem::Separator)
+        .add_submenu(SystemTraySubmenu::new("Server", server_menu))
+        .add_item(action_item(Action::OpenSettings, "Settings", shortcuts))
+        .add_item(CustomMenuItem::new(
+            "open_logs_directory".to_string(),
+            "Open Logs",
+        ))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new(
+            "check_for_updates".to_string(),
+            format!("Check for Updates ({})", env!("CARGO_PKG_VERSION")),
+        ))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("quit_application".to_string(), "Quit"))
+}
+
+// Rebuild the tray menu after settings or shortcuts that it shows have changed.
+pub fn refresh(app_handle: &tauri::AppHandle, settings: &Settings) {
+    let shortcuts = app_handle.state::<Shortcuts>().registrations();
+    let status = app_handle.state::<HomeAssistantClient>().status();
+    if let Err(e) = app_handle
+        .tray_handle()
+        .set_menu(build_menu(settings, &shortcuts, &status))
+    {
+        log::warn!("Could not update tray menu: {}", e);
+    }
+}
+
+pub fn set_connection_status(app_handle: &tauri::AppHandle, status: &ConnectionStatus) {
+    let Some(item) = app_handle
+        .tray_handle()
+        .try_get_item(CONNECTION_STATUS_ITEM)
+    else {
+        return;
+    };
+    if let Err(e) = item.set_title(status.to_string()) {
+        log::warn!("Could not update tray menu: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(
+        accelerator: &str,
+        action: Action,
+        error: Option<&str>,
+    ) -> ShortcutRegistration {
+        ShortcutRegistration {
+            accelerator: accelerator.to_string(),
+            action,
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn labels_items_with_their_live_shortcut() {
+        let shortcuts = [
+            registration(
+                "Ctrl+Alt+A",
+                Action::ToggleWindow,
+                Some("already registered"),
+            ),
+            registration("F8", Action::ToggleWindow, None),
+            registration("F9", Action::QuickCommand("Lights off".to_string()), None),
+        ];
+
+        assert_eq!(
+            action_label(&Action::ToggleWindow, "Show/Hide Window", &shortcuts),
+            "Show/Hide Window (F8)"
+        );
+        assert_eq!(
+            action_label(
+                &Action::QuickCommand("Lights off".to_string()),
+                "Lights off",
+                &shortcuts
+            ),
+            "Lights off (F9)"
+        );
+        assert_eq!(
+            action_label(
+                &Action::QuickCommand("Lights on".to_string()),
+                "Lights on",
+                &shortcuts
+            ),
+            "Lights on"
+        );
+    }
+
+    #[test]
+    fn leaves_out_shortcuts_that_failed() {
+        let shortcuts = [registration(
+            "Ctrl+Shift+A",
+            Action::TriggerVoicePipeline,
+            Some("already registered"),
+        )];
+        assert_eq!(
+            action_label(
+                &Action::TriggerVoicePipeline,
+                "Trigger Voice Pipeline",
+                &shortcuts
+            ),
+            "Trigger Voice Pipeline"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{Manager, PhysicalPosition, PhysicalSize};
+
+use crate::settings::{SettingsStore, WindowGeometry};
+
+// Moving a window fires a stream of events; save once it has come to rest.
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Sends window positions to the thread saving them in settings.
+pub struct WindowStateSaver(Mutex<Sender<(String, WindowGeometry)>>);
+
+// Start the thread saving window positions, once the settings store is managed.
+pub fn start(app_handle: tauri::AppHandle) -> WindowStateSaver {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || save_changes(&app_handle, rx));
+    WindowStateSaver(Mutex::new(tx))
+}
+
+fn save_changes(app_handle: &tauri::AppHandle, rx: Receiver<(String, WindowGeometry)>) {
+    while let Ok(mut latest) = rx.recv() {
+        while let Ok(next) = rx.recv_timeout(SAVE_DEBOUNCE) {
+            latest = next;
+        }
+
+        let (monitor, geometry) = latest;
+        let result = app_handle.state::<SettingsStore>().update(|settings| {
+            settings.window_state.last_monitor = Some(monitor.clone());
+            settings.window_state.monitors.insert(monitor, geometry);
+        });
+        if let Err(e) = result {
+            log::warn!("Could not save window position: {}", e);
+        }
+    }
+}
+
+// Monitors are told apart by name and resolution, so the same screen at a
+// different resolution gets its own position.
+fn monitor_key(monitor: &tauri::Monitor) -> String {
+    let size = monitor.size();
+    format!(
+        "{}@{}x{}",
+        monitor.name().map_or("unknown", String::as_str),
+        size.width,
+        size.height
+    )
+}
+
+// Remember where the window is, after it was moved or resized.
+pub fn remember(window: &tauri::Window) {
+    // Hidden and minimized windows report positions far off screen.
+    if !window.is_visible().unwrap_or(false) || window.is_minimized().unwrap_or(true) {
+        return;
+    }
+    let (Ok(position), Ok(size), Ok(Some(monitor))) = (
+        window.outer_position(),
+        window.inner_size(),
+        window.current_monitor(),
+    ) else {
+        return;
+    };
+    if size.width == 0 || size.height == 0 {
+        return;
+    }
+
+    let geometry = WindowGeometry {
+        x: position.x,
+        y: position.y,
+        width: size.width,
+        height: size.height,
+    };
+    // Events can arrive before the app is set up.
+    if let Some(saver) = window.try_state::<WindowStateSaver>() {
+        let _ = saver
+            .0
+            .lock()
+            .unwrap()
+            .send((monitor_key(&monitor), geometry));
+    }
+}
+
+// Put the window back where the user last had it. If that monitor has gone
+// away, use the position saved for the primary monitor, or failing that move
+// the window onto it.
+pub fn restore(window: &tauri::Window) {
+    let Some(store) = window.try_state::<SettingsStore>() else {
+        return;
+    };
+    let state = store.read(|settings| settings.window_state.clone());
+    let Some((last_monitor, last_geometry)) = state
+        .last_monitor
+        .as_ref()
+        .and_then(|key| Some((key, *state.monitors.get(key)?)))
+    else {
+        return;
+    };
+
+    let monitors = window.available_monitors().unwrap_or_default();
+    let (geometry, monitor) = match monitors
+        .into_iter()
+        .find(|monitor| monitor_key(monitor) == *last_monitor)
+    {
+        Some(monitor) => (last_geometry, monitor),
+        None => {
+            let Ok(Some(primary)) = window.primary_monitor() else {
+                return;
+            };
+            let geometry = state
+                .monitors
+                .get(&monitor_key(&primary))
+                .copied()
+                .unwrap_or(last_geometry);
+            (geometry, primary)
+        }
+    };
+
+    let geometry = clamp(geometry, *monitor.position(), *monitor.size());
+    log::info!(
+        "Restoring window to {}x{} at {},{}",
+        geometry.width,
+        geometry.height,
+        geometry.x,
+        geometry.y
+    );
+    if let Err(e) = window.set_size(PhysicalSize::new(geometry.width, geometry.height)) {
+        log::warn!("Could not restore window size: {}", e);
+    }
+    if let Err(e) = window.set_position(PhysicalPosition::new(geometry.x, geometry.y)) {
+        log::warn!("Could not restore window position: {}", e);
+    }
+}
+
+// Keep the whole window inside a monitor at `position` with `size`.
+fn clamp(
+    geometry: WindowGeometry,
+    position: PhysicalPosition<i32>,
+    size: PhysicalSize<u32>,
+) -> WindowGeometry {
+    let width = geometry.width.min(size.width);
+    let height = geometry.height.min(size.height);
+    WindowGeometry {
+        x: geometry
+            .x
+            .clamp(position.x, position.x + (size.width - width) as i32),
+        y: geometry
+            .y
+            .clamp(position.y, position.y + (size.height - height) as i32),
+        width,
+        height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geometry(x: i32, y: i32, width: u32, height: u32) -> WindowGeometry {
+        WindowGeometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn clamp_to_full_hd(geometry: WindowGeometry) -> WindowGeometry {
+        clamp(
+            geometry,
+            PhysicalPosition::new(1920, 0),
+            PhysicalSize::new(1920, 1080),
+        )
+    }
+
+    #[test]
+    fn keeps_windows_inside_the_monitor() {
+        let inside = geometry(2000, 100, 800, 600);
+        assert_eq!(clamp_to_full_hd(inside), inside);
+    }
+
+    #[test]
+    fn moves_windows_onto_the_monitor() {
+        // Left of and above it.
+        assert_eq!(
+            clamp_to_full_hd(geometry(0, -50, 800, 600)),
+            geometry(1920, 0, 800, 600)
+        );
+        // Partly off its right and bottom edges.
+        assert_eq!(
+            clamp_to_full_hd(geometry(3500, 900, 800, 600)),
+            geometry(3040, 480, 800, 600)
+        );
+    }
+
+    #[test]
+    fn shrinks_windows_larger_than_the_monitor() {
+        assert_eq!(
+            clamp_to_full_hd(geometry(1800, -10, 2560, 1440)),
+            geometry(1920, 0, 1920, 1080)
+        );
+    }
+}
@@ -8,21 +8,34 @@ import {
   getUser,
   subscribeConfig,
 } from "home-assistant-js-websocket";
-import { info, warn } from "tauri-plugin-log-api";
+import { invoke } from "@tauri-apps/api/tauri";
+import { listen } from "@tauri-apps/api/event";
+import { info } from "tauri-plugin-log-api";
 
-import { type HomeAssistantSettings } from "../types/settings";
+import { type ConnectionStatus } from "../types/connectionStatus";
+import {
+  type HomeAssistantSettings,
+  type HomeAssistantUrls,
+} from "../types/settings";
 import {
-  type PipelineRun,
-  type PipelineRunEvent,
-  type PipelineRunOptions,
   type AssistPipeline,
   type AssistPipelineMutableParams,
 } from "../types/homeAssistantAssist";
 
-export function generateHomeAssistantURLFromSettings(settings: HomeAssistantSettings): string {
-  return `${settings.ssl ? "https" : "http"}://${settings.host}${
-    settings.port === 443 ? "" : `:${settings.port}`
-  }`;
+// Resolves once the app's own connection to Home Assistant is up.
+async function appConnected(): Promise<void> {
+  let connected: () => void = () => {};
+  const done = new Promise<void>((resolve) => (connected = resolve));
+  const unlisten = await listen<ConnectionStatus>(
+    "connection-status",
+    (event) => {
+      if (event.payload.state === "connected") connected();
+    }
+  );
+  const status = await invoke<ConnectionStatus>("get_connection_status");
+  if (status.state === "connected") connected();
+  await done;
+  unlisten();
 }
 
 export class HomeAssistant {
@@ -30,6 +43,7 @@ export class HomeAssistant {
 
   private auth: Auth | null = null;
   private config: HomeAssistantSettings | null = null;
+  private urls: HomeAssistantUrls | null = null;
   private connectedCallback: (connection: Connection, user: HassUser) => void;
   private configCallback: (config: HassConfig) => void;
 
@@ -37,6 +51,7 @@ export class HomeAssistant {
     connectedCallback: (connection: Connection, user: HassUser) => void,
     configReceivedCallback: (config: HassConfig) => void,
     config?: HomeAssistantSettings,
+    urls?: HomeAssistantUrls,
     connection?: Connection
   ) {
     info("Home Assistant: create new client");
@@ -44,6 +59,7 @@ export class HomeAssistant {
     this.connectedCallback = connectedCallback;
     this.configCallback = configReceivedCallback;
     this.config = config || null;
+    this.urls = urls || null;
     this.connection = connection || null;
   }
 
@@ -61,13 +77,11 @@ export class HomeAssistant {
 
   async connect(): Promise<void> {
     if (this.connection) return;
-    if (!this.config?.host) throw new Error("Missing Home Assistant host");
+    if (!this.urls?.base_url) throw new Error("Missing Home Assistant URL");
     if (!this.config?.access_token)
       throw new Error("Missing Home Assistant access token");
 
-    const url = `${this.config.ssl ? "https" : "http"}://${this.config.host}:${
-      this.config.port
-    }`;
+    const url = this.urls.base_url;
 
     info(`Home Assistant: ${url}`);
 
@@ -85,7 +99,9 @@ export class HomeAssistant {
 
     this.connection.addEventListener("disconnected", () => {
       info("Disconnected from Home Assistant");
-      if (this.connection) this.connection.reconnect();
+      // Reconnect once the app's own connection is back, so retries follow
+      // its backoff and stop when the access token is rejected.
+      this.connection?.suspendReconnectUntil(appConnected());
     });
 
     subscribeConfig(this.connection, (config: HassConfig) => {
@@ -97,96 +113,6 @@ export class HomeAssistant {
     });
   }
 
-  processEvent = (
-    run: PipelineRun | undefined,
-    event: PipelineRunEvent,
-    options?: PipelineRunOptions
-  ): PipelineRun | undefined => {
-    if (event.type === "run-start") {
-      run = {
-        init_options: options,
-        stage: "ready",
-        run: event.data,
-        events: [event],
-      };
-      return run;
-    }
-
-    if (!run) {
-      warn(
-        `Received unexpected event before receiving session: ${JSON.stringify(
-          event
-        )}`
-      );
-      return undefined;
-    }
-
-    if (event.type === "wake_word-start") {
-      run = {
-        ...run,
-        stage: "wake_word",
-        wake_word: { ...event.data, done: false },
-      };
-    } else if (event.type === "wake_word-end") {
-      run = {
-        ...run,
-        wake_word: { ...run.wake_word!, ...event.data, done: true },
-      };
-    } else if (event.type === "stt-start") {
-      run = {
-        ...run,
-        stage: "stt",
-        stt: { ...event.data, done: false },
-      };
-    } else if (event.type === "stt-end") {
-      run = {
-        ...run,
-        stt: { ...run.stt!, ...event.data, done: true },
-      };
-    } else if (event.type === "intent-start") {
-      run = {
-        ...run,
-        stage: "intent",
-        intent: { ...event.data, done: false },
-      };
-    } else if (event.type === "intent-end") {
-      run = {
-        ...run,
-        intent: { ...run.intent!, ...event.data, done: true },
-      };
-    } else if (event.type === "tts-start") {
-      run = {
-        ...run,
-        stage: "tts",
-        tts: { ...event.data, done: false },
-      };
-    } else if (event.type === "tts-end") {
-      run = {
-        ...run,
-        tts: { ...run.tts!, ...event.data, done: true },
-      };
-    } else if (event.type === "run-end") {
-      run = { ...run, stage: "done" };
-    } else if (event.type === "error") {
-      run = { ...run, stage: "error", error: event.data };
-    } else {
-      run = { ...run };
-    }
-
-    run.events = [...run.events, event];
-
-    return run;
-  };
-
-  runAssistPipeline = (
-    options: PipelineRunOptions,
-    callback: (event: PipelineRunEvent) => void
-  ) =>
-    this.connection?.subscribeMessage<PipelineRunEvent>(callback, {
-      ...options,
-      type: "assist_pipeline/run",
-    });
-
   listAssistPipelines = () =>
     this.connection?.sendMessagePromise<{
       pipelines: AssistPipeline[];
@@ -0,0 +1,10 @@
+import type { SettingsFieldError } from "./settings";
+
+// What a command rejects with, see `CommandError` in src-tauri/src/error.rs.
+export type ErrorCode = "io" | "config" | "window" | "network" | "tls" | "auth";
+
+export interface CommandError {
+  code: ErrorCode;
+  message: string;
+  errors?: SettingsFieldError[];
+}
@@ -0,0 +1,27 @@
+import { type PipelineRun } from "./homeAssistantAssist";
+
+// A command given while Home Assistant couldn't be reached, see
+// `QueuedCommand` in src-tauri/src/home_assistant/queue.rs.
+export interface QueuedCommand {
+  id: number;
+  text: string;
+  profile: string;
+  pipeline: string | null;
+  time_sensitive: boolean;
+  // Seconds since the Unix epoch.
+  queued_at: number;
+  expires_at: number;
+}
+
+// Result of `run_pipeline`.
+export type PipelineOutcome =
+  | { result: "finished"; run: PipelineRun }
+  | { result: "queued"; command: QueuedCommand };
+
+// Sent as `queued-command` events once a queued command was replayed or
+// dropped.
+export type ReplayResult = { command: QueuedCommand } & (
+  | { outcome: "sent"; reply: string | null }
+  | { outcome: "failed"; message: string }
+  | { outcome: "expired" }
+);
@@ -0,0 +1,36 @@
+// The state of the app's own connection to Home Assistant, see
+// `ConnectionStatus` in src-tauri/src/home_assistant/supervisor.rs.
+export type ConnectionStatus =
+  | { state: "not_configured" }
+  | { state: "connecting"; attempt: number }
+  | { state: "connected"; ha_version: string }
+  | { state: "auth_failed"; message: string }
+  | {
+      state: "backoff";
+      reason: "unreachable" | "connection_lost";
+      retry_in_seconds: number;
+      message: string;
+    }
+  | { state: "rest_only" };
+
+// Why `test_connection` failed.
+export type TestFailure =
+  | "dns"
+  | "tcp"
+  | "tls"
+  | "unauthorized"
+  | "forbidden"
+  | "timeout"
+  | "other";
+
+// Result of `test_connection`, see `ConnectionTest` in
+// src-tauri/src/home_assistant/connection_test.rs.
+export type ConnectionTest =
+  | {
+      result: "passed";
+      ha_version: string;
+      user_name: string;
+      is_admin: boolean;
+      latency_ms: number;
+    }
+  | { result: "failed"; failure: TestFailure; message: string };
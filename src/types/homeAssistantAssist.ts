@@ -106,6 +106,12 @@ export interface AssistPipelineMutableParams {
   wake_word_id: string | null;
 }
 
+// Returned by `assist_pipeline/pipeline/list` and the `list_pipelines` command.
+export interface AssistPipelineList {
+  pipelines: AssistPipeline[];
+  preferred_pipeline: string | null;
+}
+
 export interface assistRunListing {
   pipeline_run_id: string;
   timestamp: string;
@@ -122,7 +128,7 @@ interface PipelineRunStartEvent extends PipelineEventBase {
     language: string;
     runner_data: {
       stt_binary_handler_id: number | null;
-      timeout: number;
+      timeout?: number;
     };
   };
 }
@@ -233,9 +239,9 @@ export type PipelineRunOptions = (
   conversation_id?: string | null;
 };
 
+// Returned by `run_pipeline`, see `PipelineRun` in
+// src-tauri/src/home_assistant/assist.rs.
 export interface PipelineRun {
-  init_options?: PipelineRunOptions;
-  events: PipelineRunEvent[];
   stage: "ready" | "wake_word" | "stt" | "intent" | "tts" | "done" | "error";
   run: PipelineRunStartEvent["data"];
   error?: PipelineErrorEvent["data"];
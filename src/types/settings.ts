@@ -1,16 +1,94 @@
+// Mirrors the Rust settings types. The `matches_the_typescript_types` test in
+// src-tauri/src/settings.rs checks these against the settings schema.
+
 export interface HomeAssistantSettings {
   access_token?: string;
-  host: string;
-  port: number;
-  ssl: boolean;
+  access_token_secret?: string;
+  base_url: string;
+  rest_only: boolean;
+}
+
+export interface HomeAssistantUrls {
+  base_url: string;
+  websocket_url: string;
 }
 
+// Serialized like the Rust `Action` enum: plain strings for actions without
+// an argument, single key objects for the others.
+export type Action =
+  | "none"
+  | "toggle_window"
+  | "trigger_voice_pipeline"
+  | "open_settings"
+  | "new_conversation"
+  | { quick_command: string }
+  | { switch_pipeline: string };
+
 export interface TraySettings {
-  double_click_action: string;
+  left_click: Action;
+  double_click: Action;
+}
+
+export interface SttSettings {
+  backend: "openai" | "whisper_asr";
+  endpoint: string | null;
+  model: string;
+  language: string | null;
+  api_key?: string;
+  api_key_secret?: string;
+  timeout_seconds: number;
+}
+
+export interface NetworkSettings {
+  proxy: string | null;
+  no_proxy: string[];
+  ca_certificates: string[];
+}
+
+export interface WindowGeometry {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+// Kept up to date by the app, changes made here are ignored.
+export interface WindowState {
+  last_monitor: string | null;
+  monitors: Record<string, WindowGeometry>;
 }
 
 export interface Settings {
-  autostart?: boolean;
-  home_assistant: HomeAssistantSettings;
+  schema_version?: number;
+  autostart: boolean;
+  active_profile: string;
+  profiles: Record<string, HomeAssistantSettings>;
   tray: TraySettings;
+  quick_commands: Record<string, string>;
+  shortcuts: Record<string, Action>;
+  stt: SttSettings;
+  network: NetworkSettings;
+  window_state: WindowState;
+}
+
+export interface ShortcutRegistration {
+  accelerator: string;
+  action: Action;
+  error?: string;
+}
+
+export interface SettingsFieldError {
+  field: string;
+  code: string;
+  message: string;
+}
+
+export interface SettingsChanged {
+  source: "app" | "file";
+  settings: Settings;
+}
+
+export interface EffectiveSettings {
+  settings: Settings;
+  sources: Record<string, "default" | "file" | "environment" | "cli">;
 }